@@ -0,0 +1,66 @@
+use crate::{ScopedVec, Storage};
+
+/// Iterator returned by [ScopedVec::drain_batched].
+pub struct DrainBatched<T, S = Vec<T>> {
+    /// Scopes still to be drained, the next on top.
+    stack: Vec<ScopedVec<T, S>>,
+    /// Values taken out of the most recently drained scope which haven't
+    /// been yielded yet.
+    pending: std::vec::IntoIter<T>,
+    batch_size: usize,
+}
+
+impl<T, S: Storage<T>> DrainBatched<T, S> {
+    pub(crate) fn new(vec: &ScopedVec<T, S>, batch_size: usize) -> Self {
+        assert!(batch_size != 0, "drain_batched batch_size must be non-zero");
+
+        Self {
+            stack: vec![vec.clone()],
+            pending: Vec::new().into_iter(),
+            batch_size
+        }
+    }
+}
+
+impl<T, S: Storage<T>> Iterator for DrainBatched<T, S> {
+    type Item = Vec<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut batch = Vec::with_capacity(self.batch_size);
+
+        loop {
+            batch.extend(self.pending.by_ref().take(self.batch_size - batch.len()));
+            if batch.len() == self.batch_size {
+                return Some(batch);
+            }
+
+            let scope = match self.stack.pop() {
+                Some(scope) => scope,
+                None => return if batch.is_empty() { None } else { Some(batch) },
+            };
+
+            let mut values = Vec::new();
+            scope.write_local(|local| local.take_all_into(&mut values));
+            self.pending = values.into_iter();
+            self.stack.extend(scope.child_handles().into_iter().rev());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assert_structure, nested, ScopedVec};
+
+    #[test]
+    fn drains_in_batches_leaving_empty_scopes() {
+        let root = ScopedVec::from_nested(nested!([1, 2; [3, 4, 5; [6]], [7]]));
+
+        let mut batches = root.drain_batched(3);
+        assert_eq!(batches.next(), Some(vec![1, 2, 3]));
+        assert_structure!(root, [; [; [6]], [7]]);
+
+        assert_eq!(batches.collect::<Vec<_>>(), vec![vec![4, 5, 6], vec![7]]);
+        assert_structure!(root, [; [; []], []]);
+        assert_eq!(root.drain_batched(3).next(), None);
+    }
+}
@@ -0,0 +1,139 @@
+use std::error::Error;
+use std::fmt;
+
+/// The ways in which fallible operations on a
+/// [ScopedVec](crate::ScopedVec) can fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ScopedVecError {
+    /// The path doesn't lead to a scope, `depth` being the position in
+    /// `path` of the first index which doesn't exist.
+    InvalidPath { path: Vec<usize>, depth: usize },
+    /// An empty path was given to an operation which needs the scope
+    /// it points to to have a parent.
+    EmptyPath,
+    /// A lock guarding the tree was poisoned by a thread panicking part
+    /// way through an update.
+    Poisoned,
+    /// The operation would have had to wait for a lock held by another
+    /// thread.
+    WouldBlock,
+    /// The operation couldn't complete before its deadline.
+    Timeout,
+    /// The scope being attached is still attached to a parent, and would
+    /// end up shared between two places.
+    Attached,
+    /// The scope being attached is the scope it would be attached under
+    /// or one of its ancestors, which would make the tree contain itself.
+    Cycle,
+}
+
+impl fmt::Display for ScopedVecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidPath { path, depth } => write!(f, "no scope at index {} of path {:?}", depth, path),
+            Self::EmptyPath => f.write_str("operation requires a non-empty path"),
+            Self::Poisoned => f.write_str("lock poisoned by a panic part way through an update"),
+            Self::WouldBlock => f.write_str("operation would block"),
+            Self::Timeout => f.write_str("operation timed out"),
+            Self::Attached => f.write_str("scope is still attached to a parent"),
+            Self::Cycle => f.write_str("scope would become its own descendant"),
+        }
+    }
+}
+
+impl Error for ScopedVecError {}
+
+/// The error returned by [ScopedVec::try_push](crate::ScopedVec::try_push),
+/// handing back the value that couldn't be pushed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TryPushError<T> {
+    /// The value that was rejected.
+    pub value: T,
+    /// Why the value was rejected.
+    pub error: ScopedVecError,
+}
+
+impl<T> TryPushError<T> {
+    /// Take back the value that was rejected.
+    pub fn into_value(self) -> T {
+        self.value
+    }
+}
+
+impl<T> fmt::Display for TryPushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to push value: {}", self.error)
+    }
+}
+
+impl<T: fmt::Debug> Error for TryPushError<T> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl<T> From<TryPushError<T>> for ScopedVecError {
+    fn from(e: TryPushError<T>) -> Self {
+        e.error
+    }
+}
+
+/// The error returned by [ScopedVec::try_map](crate::ScopedVec::try_map)
+/// when the conversion fails for one of the values, locating the value
+/// that couldn't be converted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapError<E> {
+    /// The error returned by the conversion.
+    pub error: E,
+    /// The path to the scope holding the value, relative to the scope
+    /// `try_map` was called on, see [ScopedVec::scope_at_path](crate::ScopedVec::scope_at_path).
+    pub path: Vec<usize>,
+    /// The index of the value within the scope's own values.
+    pub index: usize,
+}
+
+impl<E: fmt::Display> fmt::Display for MapError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to map value {} of scope at path {:?}: {}", self.index, self.path, self.error)
+    }
+}
+
+impl<E: Error + 'static> Error for MapError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// The error returned by [ScopedVec::zip_with](crate::ScopedVec::zip_with)
+/// when the two trees don't have the same shape, locating the first
+/// scope where they diverge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructureMismatch {
+    /// The path to the diverging scope, relative to the scopes being
+    /// compared.
+    pub path: Vec<usize>,
+    pub kind: MismatchKind,
+}
+
+/// How two scopes at the same position in a pair of trees differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchKind {
+    /// The scopes hold a different number of values of their own.
+    LocalLength { left: usize, right: usize },
+    /// The scopes have a different number of children.
+    ChildCount { left: usize, right: usize },
+}
+
+impl fmt::Display for StructureMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            MismatchKind::LocalLength { left, right } =>
+                write!(f, "scopes at path {:?} hold {} and {} values", self.path, left, right),
+            MismatchKind::ChildCount { left, right } =>
+                write!(f, "scopes at path {:?} have {} and {} children", self.path, left, right),
+        }
+    }
+}
+
+impl Error for StructureMismatch {}
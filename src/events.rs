@@ -0,0 +1,91 @@
+use crate::{ScopedVec, ScopedVecGuardHolder, Storage};
+use owning_ref::OwningHandle;
+use std::iter;
+
+/// A single step of a depth first walk over a tree, as yielded by
+/// [ScopedVec::iter_events].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IterEvent<'a, T> {
+    /// The walk has entered a scope, `depth` levels below the scope the
+    /// walk started from. `index_in_parent` is the position of the scope
+    /// within its parent's children, or `None` for the starting scope.
+    EnterScope { depth: usize, index_in_parent: Option<usize> },
+    /// A value held directly by the most recently entered scope.
+    Value(&'a T),
+    /// The walk has finished with the most recently entered scope and
+    /// all of its descendants.
+    LeaveScope,
+}
+
+type BoxedIter<'a, T> = Box<dyn Iterator<Item = IterEvent<'a, T>> + 'a>;
+
+/// Iterator returned by [ScopedVec::iter_events].
+pub struct EventIterator<'a, T, S = Vec<T>> {
+    iterator: OwningHandle<Box<ScopedVecGuardHolder<'a, T, S>>, BoxedIter<'a, T>>,
+}
+
+impl<'a, T, S: Storage<T>> EventIterator<'a, T, S> {
+    pub(crate) fn new(vec: &'a ScopedVec<T, S>, depth: usize, index_in_parent: Option<usize>) -> Self {
+        Self {
+            iterator: OwningHandle::new_with_fn(
+                Box::new(ScopedVecGuardHolder::read(vec)),
+                |g| {
+                    // the value behind the raw pointer `g` is boxed, so we're safe to dereference
+                    let guards = unsafe { &*g };
+
+                    let children = guards.children.iter()
+                        .enumerate()
+                        .flat_map(move |(i, child)| EventIterator::new(child, depth + 1, Some(i)));
+
+                    Box::new(iter::once(IterEvent::EnterScope { depth, index_in_parent })
+                        .chain(guards.inner.values.iter().map(IterEvent::Value))
+                        .chain(children)
+                        .chain(iter::once(IterEvent::LeaveScope))) as BoxedIter<'a, T>
+                }
+            )
+        }
+    }
+}
+
+impl<'a, T, S: Storage<T>> Iterator for EventIterator<'a, T, S> {
+    type Item = IterEvent<'a, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iterator.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IterEvent::{self, EnterScope, LeaveScope, Value};
+    use crate::{nested, ScopedVec};
+
+    #[test]
+    fn events_follow_the_tree() {
+        let root = ScopedVec::from(nested!([3; [4; [5]], [6]]));
+        let events: Vec<_> = root.iter_events().collect();
+
+        assert_eq!(events, vec![
+            EnterScope { depth: 0, index_in_parent: None },
+            Value(&3),
+            EnterScope { depth: 1, index_in_parent: Some(0) },
+            Value(&4),
+            EnterScope { depth: 2, index_in_parent: Some(0) },
+            Value(&5),
+            LeaveScope,
+            LeaveScope,
+            EnterScope { depth: 1, index_in_parent: Some(1) },
+            Value(&6),
+            LeaveScope,
+            LeaveScope,
+        ]);
+
+        let values: Vec<_> = root.iter_events()
+            .filter_map(|e| match e {
+                IterEvent::Value(v) => Some(v),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(values, root.iter().collect::<Vec<_>>());
+    }
+}
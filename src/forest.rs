@@ -0,0 +1,144 @@
+use crate::{ScopedVec, Storage};
+use std::fmt;
+
+/// An ordered collection of independent root `ScopedVec`s, such as one
+/// per subsystem, which can be read from as a single unit.
+///
+/// The forest only holds handles to its roots, so the roots behave
+/// exactly as they would outside of it and can still be pushed to and
+/// scoped from anywhere else they're held.
+pub struct ScopedVecForest<T, S = Vec<T>> {
+    roots: Vec<ScopedVec<T, S>>,
+}
+
+impl<T, S: Storage<T>> ScopedVecForest<T, S> {
+    pub fn new() -> Self {
+        Self {
+            roots: Vec::new()
+        }
+    }
+
+    /// Create a new, empty root at the end of this forest and return a
+    /// handle to it.
+    pub fn new_root(&mut self) -> ScopedVec<T, S> {
+        let root = ScopedVec::with_storage();
+        self.roots.push(root.clone());
+        root
+    }
+
+    /// Add an existing tree to the end of this forest.
+    pub fn add_root(&mut self, root: ScopedVec<T, S>) {
+        self.roots.push(root);
+    }
+
+    /// Remove `root` from this forest, returning false if it wasn't
+    /// one of this forest's roots. Roots are compared by identity, see
+    /// [ScopedVec::ptr_eq].
+    pub fn remove_root(&mut self, root: &ScopedVec<T, S>) -> bool {
+        match self.roots.iter().position(|r| r.ptr_eq(root)) {
+            Some(i) => {
+                self.roots.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns the roots of this forest in the order they were added.
+    pub fn roots(&self) -> &[ScopedVec<T, S>] {
+        &self.roots
+    }
+
+    /// Iterate over the values of every root in turn, each in the order
+    /// given by [ScopedVec::iter].
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.roots.iter().flat_map(ScopedVec::iter)
+    }
+
+    /// Returns the number of values held across every root.
+    pub fn len(&self) -> usize {
+        self.roots.iter().map(ScopedVec::total_len).sum()
+    }
+
+    /// Check if none of the roots hold any values.
+    pub fn is_empty(&self) -> bool {
+        self.roots.iter().all(ScopedVec::is_empty_deep)
+    }
+
+    pub fn contains(&self, val: &T) -> bool
+        where T: PartialEq
+    {
+        self.roots.iter().any(|root| root.contains(val))
+    }
+
+    /// Returns the first value, in the order given by
+    /// [ScopedVecForest::iter], for which `predicate` returns true.
+    pub fn find<P: FnMut(&&T) -> bool>(&self, predicate: P) -> Option<&T> {
+        self.iter().find(predicate)
+    }
+}
+
+impl<T, S: Storage<T>> Default for ScopedVecForest<T, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, S> Clone for ScopedVecForest<T, S> {
+    fn clone(&self) -> Self {
+        Self {
+            roots: self.roots.clone()
+        }
+    }
+}
+
+impl<T: fmt::Debug, S: Storage<T>> fmt::Debug for ScopedVecForest<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopedVecForest")
+            .field("roots", &self.roots)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScopedVecForest;
+    use crate::ScopedVec;
+
+    #[test]
+    fn aggregates_across_roots() {
+        let mut forest = ScopedVecForest::new();
+
+        let network = forest.new_root();
+        network.push(1);
+        network.scope().push(2);
+
+        let storage = ScopedVec::new();
+        storage.push(3);
+        let nested = storage.scope();
+        nested.push(4);
+        nested.scope().push(5);
+        forest.add_root(storage.clone());
+
+        let ui = forest.new_root();
+        ui.scope().scope().push(6);
+
+        assert_eq!(forest.roots().len(), 3);
+        assert_eq!(forest.iter().copied().collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(forest.len(), 6);
+        assert!(!forest.is_empty());
+        assert!(forest.contains(&5));
+        assert!(!forest.contains(&7));
+        assert_eq!(forest.find(|v| **v > 3), Some(&4));
+
+        // the roots are unaffected by being in a forest
+        assert_eq!(storage, [3, 4, 5]);
+        nested.push(7);
+        assert!(forest.contains(&7));
+
+        assert!(forest.remove_root(&storage));
+        assert!(!forest.remove_root(&storage));
+        assert_eq!(forest.iter().copied().collect::<Vec<_>>(), vec![1, 2, 6]);
+        assert_eq!(storage, [3, 4, 7, 5]);
+    }
+}
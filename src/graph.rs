@@ -0,0 +1,101 @@
+use crate::{lock, ScopedVec, Storage};
+use petgraph::graph::{DiGraph, NodeIndex};
+
+/// A scope in the graph built by [ScopedVec::to_graph].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ScopeNode {
+    /// The path to the scope from the one the graph was built from,
+    /// which identifies it within the graph. See
+    /// [ScopedVec::scope_at_path].
+    pub path: Vec<usize>,
+    /// The number of levels the scope is below the one the graph was
+    /// built from.
+    pub depth: usize,
+    /// The number of values held directly by the scope.
+    pub local_len: usize,
+}
+
+/// A node in the graph built by [ScopedVec::to_graph_with_values].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GraphNode<T> {
+    Scope(ScopeNode),
+    Value(T),
+}
+
+/// Integration with [petgraph], exporting the shape of a tree for
+/// structural analysis.
+impl<T, S: Storage<T>> ScopedVec<T, S> {
+    /// Build a graph with a node for this scope and each of its
+    /// descendants, and an edge from each scope to each of its children.
+    /// Each scope is read under its own lock, so the graph is consistent
+    /// per scope but not across the whole tree if it's being modified.
+    pub fn to_graph(&self) -> DiGraph<ScopeNode, ()> {
+        self.build_graph(|graph, node, _| graph.add_node(node))
+    }
+
+    /// Like [ScopedVec::to_graph], but with an additional leaf node for
+    /// every value, with an edge from the scope holding it.
+    pub fn to_graph_with_values(&self) -> DiGraph<GraphNode<T>, ()>
+        where T: Clone
+    {
+        self.build_graph(|graph, node, values| {
+            let scope = graph.add_node(GraphNode::Scope(node));
+            for val in values {
+                let value = graph.add_node(GraphNode::Value(val.clone()));
+                graph.add_edge(scope, value, ());
+            }
+            scope
+        })
+    }
+
+    fn build_graph<N, F>(&self, mut add_scope: F) -> DiGraph<N, ()>
+        where F: FnMut(&mut DiGraph<N, ()>, ScopeNode, &[T]) -> NodeIndex
+    {
+        let mut graph = DiGraph::new();
+        // the nodes of the scopes on the path to the one being visited
+        let mut ancestors = Vec::new();
+
+        self.walk_with_paths(|path, scope| {
+            let index = {
+                let local = lock::read(&scope.node.inner);
+                let node = ScopeNode {
+                    depth: path.len(),
+                    local_len: local.values.len(),
+                    path: path.to_vec()
+                };
+                add_scope(&mut graph, node, local.values.as_slice())
+            };
+
+            ancestors.truncate(path.len());
+            if let Some(&parent) = ancestors.last() {
+                graph.add_edge(parent, index, ());
+            }
+            ancestors.push(index);
+        });
+
+        graph
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GraphNode, ScopeNode};
+    use crate::{nested, ScopedVec};
+
+    #[test]
+    fn exports_scopes_as_nodes() {
+        let root = ScopedVec::from(nested!([1, 2; [3; [4, 5], []], [6]]));
+
+        let graph = root.to_graph();
+        assert_eq!(graph.node_count(), 5);
+        assert_eq!(graph.edge_count(), 4);
+
+        let grandchild = graph.node_weights().find(|n| n.path == vec![0, 0]).unwrap();
+        assert_eq!(grandchild, &ScopeNode { path: vec![0, 0], depth: 2, local_len: 2 });
+
+        let graph = root.to_graph_with_values();
+        assert_eq!(graph.node_count(), 11);
+        assert_eq!(graph.edge_count(), 10);
+        assert_eq!(graph.node_weights().filter(|n| matches!(n, GraphNode::Value(_))).count(), 6);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::{lock, ScopedVec, ScopedVecIterator};
+use crate::lock::Mutex;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A `ScopedVec` of strings where every distinct string is only
+/// allocated once, with each push storing a handle to the shared
+/// allocation. The interner is shared by every scope created from the
+/// same root.
+///
+/// This is useful for trees holding large numbers of mostly-duplicate
+/// strings, such as error codes or hostnames. Interned strings are never
+/// evicted, so the interner will grow with the number of distinct
+/// strings ever pushed.
+pub struct InternedScopedVec {
+    vec: ScopedVec<Arc<str>>,
+    interner: Arc<Interner>,
+}
+
+/// Counts reported by [InternedScopedVec::interner_stats].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InternerStats {
+    /// The number of distinct strings held by the interner.
+    pub unique: usize,
+    /// The number of strings pushed through the interner.
+    pub total: usize,
+}
+
+#[derive(Default)]
+struct Interner {
+    strings: Mutex<HashSet<Arc<str>>>,
+    total: AtomicUsize,
+}
+
+impl Interner {
+    fn intern(&self, s: &str) -> Arc<str> {
+        self.total.fetch_add(1, Ordering::Relaxed);
+
+        let mut strings = lock::lock(&self.strings);
+        if let Some(existing) = strings.get(s) {
+            return existing.clone();
+        }
+
+        let new: Arc<str> = Arc::from(s);
+        strings.insert(new.clone());
+        new
+    }
+}
+
+impl InternedScopedVec {
+    pub fn new() -> Self {
+        Self {
+            vec: ScopedVec::new(),
+            interner: Arc::default()
+        }
+    }
+
+    /// Create a new `InternedScopedVec` as a child of this one, sharing
+    /// its interner.
+    pub fn scope(&self) -> InternedScopedVec {
+        Self {
+            vec: self.vec.scope(),
+            interner: self.interner.clone()
+        }
+    }
+
+    /// Push a handle to the shared copy of `s` onto this scope,
+    /// allocating it if this is the first time it's been seen.
+    pub fn push_interned(&self, s: &str) {
+        let interned = self.interner.intern(s);
+        self.vec.push(interned);
+    }
+
+    pub fn iter(&self) -> ScopedVecIterator<'_, Arc<str>> {
+        self.vec.iter()
+    }
+
+    /// Returns the number of distinct strings held by the interner
+    /// against the number of strings pushed through it, across every
+    /// scope sharing the interner.
+    pub fn interner_stats(&self) -> InternerStats {
+        InternerStats {
+            unique: lock::lock(&self.interner.strings).len(),
+            total: self.interner.total.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Returns the underlying `ScopedVec` for use with the rest of its
+    /// API.
+    pub fn as_scoped_vec(&self) -> &ScopedVec<Arc<str>> {
+        &self.vec
+    }
+}
+
+impl Default for InternedScopedVec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for InternedScopedVec {
+    fn clone(&self) -> Self {
+        Self {
+            vec: self.vec.clone(),
+            interner: self.interner.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InternedScopedVec, InternerStats};
+    use std::sync::Arc;
+
+    #[test]
+    fn duplicates_share_an_allocation() {
+        let root = InternedScopedVec::new();
+        root.push_interned("ECONNRESET");
+
+        let mut scopes: Vec<_> = (0..10).map(|_| root.scope()).collect();
+        for scope in &mut scopes {
+            scope.push_interned("ECONNRESET");
+            scope.scope().push_interned("ETIMEDOUT");
+        }
+
+        let values: Vec<_> = root.iter().cloned().collect();
+        assert_eq!(values.len(), 21);
+
+        let first_reset = &values[0];
+        let first_timeout = &values[2];
+        assert_eq!(&**first_timeout, "ETIMEDOUT");
+
+        for val in &values {
+            assert!(Arc::ptr_eq(val, first_reset) || Arc::ptr_eq(val, first_timeout));
+        }
+
+        assert_eq!(root.interner_stats(), InternerStats { unique: 2, total: 21 });
+        assert_eq!(scopes[3].interner_stats(), root.interner_stats());
+    }
+}
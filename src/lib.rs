@@ -15,14 +15,14 @@
 //! Example:
 //! ```
 //! # use scoped_vec::ScopedVec;
-//! let mut root = ScopedVec::new();
+//! let root = ScopedVec::new();
 //! root.push(3);
 //!
 //! {
-//!     let mut scope1 = root.scope();
+//!     let scope1 = root.scope();
 //!     scope1.push(4);
 //!     {
-//!         let mut scope1_scope1 = scope1.scope();
+//!         let scope1_scope1 = scope1.scope();
 //!         scope1_scope1.push(5);
 //!     }
 //!
@@ -33,7 +33,7 @@
 //! }
 //!
 //! {
-//!     let mut scope2 = root.scope();
+//!     let scope2 = root.scope();
 //!     scope2.push(6);
 //! }
 //!
@@ -45,8 +45,67 @@
 //! assert_eq!(iter.next(), None);
 //! ```
 
-use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp::{Ordering, Reverse};
+use std::convert::Infallible;
+use std::collections::{BinaryHeap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, RangeBounds};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::time::{Duration, Instant};
 use owning_ref::OwningHandle;
+use lock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+mod drain;
+mod error;
+mod events;
+mod forest;
+#[cfg(feature = "petgraph")]
+mod graph;
+mod interned;
+mod local;
+mod lock;
+mod pipe;
+mod promote;
+#[cfg(feature = "log")]
+mod logger;
+mod provenance;
+#[cfg(feature = "metrics")]
+mod scope_metrics;
+mod storage;
+pub mod testing;
+mod thread_scoped;
+mod ttl;
+#[cfg(feature = "tracing-capture")]
+mod tracing_capture;
+#[cfg(feature = "tokio")]
+mod watch;
+
+pub use drain::DrainBatched;
+pub use error::{MapError, MismatchKind, ScopedVecError, StructureMismatch, TryPushError};
+pub use events::{EventIterator, IterEvent};
+pub use forest::ScopedVecForest;
+#[cfg(feature = "petgraph")]
+pub use graph::{GraphNode, ScopeNode};
+pub use interned::{InternedScopedVec, InternerStats};
+use local::Local;
+#[cfg(feature = "log")]
+pub use logger::{EnteredScope, LogRecord, ScopedLogger};
+use pipe::Observers;
+pub use pipe::PipeHandle;
+use promote::Promote;
+pub use provenance::{Provenance, ProvenanceIterator};
+pub use storage::Storage;
+pub use thread_scoped::ThreadScoped;
+pub use ttl::{Clock, SystemClock};
+#[cfg(feature = "tracing-capture")]
+pub use tracing_capture::{CapturedEvent, ScopeLayer};
 
 /// A `ScopedVec` instance can either represent the root element or a
 /// divergence of it. Refer to the crate's documentation for usage
@@ -56,239 +115,4874 @@ use owning_ref::OwningHandle;
 /// and adding a value to one of the cloned instances will result in
 /// the value being added to all instances and available for all the
 /// parent instances to iterate over.
-#[derive(Clone)]
-pub struct ScopedVec<T: Clone> {
-    inner: Arc<RwLock<Vec<T>>>,
-    children: Arc<RwLock<Vec<ScopedVec<T>>>>,
+///
+/// All mutation happens behind the scope's locks, so methods which
+/// modify a scope only need a shared reference to it. A single handle
+/// can be shared by reference between threads, such as those spawned
+/// by [std::thread::scope], without cloning it or wrapping it in
+/// another lock.
+///
+/// # Deadlocks
+///
+/// The locks aren't reentrant. Every method taking `&self` which modifies
+/// the tree waits for the write lock of each scope it changes, and
+/// iterators, [ElementGuard]s and the callbacks passed to methods such
+/// as [ScopedVec::for_each_mut] hold read or write locks while they're
+/// alive or running. Modifying a scope from the same thread while one of
+/// those holds its lock therefore never returns:
+///
+/// ```no_run
+/// # use scoped_vec::ScopedVec;
+/// let root = ScopedVec::new();
+/// root.push(1);
+///
+/// for v in root.iter() {
+///     root.push(*v); // waits forever for `root`'s write lock
+/// }
+/// ```
+///
+/// Collect what's needed first, with [ScopedVec::to_vec] for example,
+/// and modify the tree once the iterator has been dropped. Debug builds
+/// panic rather than hanging when a scope is modified while this thread
+/// is iterating over it or holding one of its values.
+pub struct ScopedVec<T, S = Vec<T>> {
+    node: Arc<Node<T, S>>,
+    /// Whether this handle is one of those counted by `Node::handles`.
+    counted: bool,
 }
 
-impl<T: Clone> ScopedVec<T> {
-    pub fn new() -> Self {
+/// The state shared between every handle to a single scope.
+struct Node<T, S> {
+    inner: RwLock<Local<T, S>>,
+    children: RwLock<Vec<ScopedVec<T, S>>>,
+    /// The scope this one is a child of. This is empty for roots, for
+    /// scopes which have been detached from their parent and once the
+    /// parent has been dropped.
+    parent: RwLock<Weak<Node<T, S>>>,
+    /// Called for every value pushed into this scope or its descendants,
+    /// see [ScopedVec::pipe_to].
+    observers: Observers<T, S>,
+    /// Told whenever the number of values in this scope or its
+    /// descendants may have changed, see [ScopedVec::watch_len].
+    #[cfg(feature = "tokio")]
+    watchers: RwLock<Vec<Arc<watch::LenWatcher>>>,
+    /// The number of handles to this scope given out by the methods
+    /// creating it and clones of them. Handles held by the tree itself,
+    /// and those looked up from it, aren't counted.
+    handles: AtomicUsize,
+    /// Whether `promote` runs once `handles` drops to zero, see
+    /// [ScopedVec::set_promote_on_drop].
+    promote_on_drop: AtomicBool,
+    promote: Promote<T, S>,
+    /// The prefix this scope's metrics were registered under, see
+    /// [ScopedVec::register_metrics].
+    #[cfg(feature = "metrics")]
+    metrics_prefix: RwLock<Option<Arc<str>>>,
+}
+
+/// An owned, recursive representation of a `ScopedVec` tree, mirroring
+/// its shape without any of the sharing semantics. Created using
+/// [ScopedVec::to_nested_vec] and turned back into a tree using
+/// [ScopedVec::from_nested].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct NestedVec<T> {
+    /// The values pushed directly into this scope.
+    pub values: Vec<T>,
+    /// The children of this scope, in the order they were created.
+    pub children: Vec<NestedVec<T>>,
+}
+
+impl<T, S> Clone for ScopedVec<T, S> {
+    fn clone(&self) -> Self {
+        if self.counted {
+            self.node.handles.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
         Self {
-            inner: Arc::new(RwLock::default()),
-            children: Arc::new(RwLock::default())
+            node: self.node.clone(),
+            counted: self.counted
         }
     }
 
-    /// Create a new `ScopedVec` as a child of this one.
-    pub fn scope(&mut self) -> ScopedVec<T> {
-        let new = ScopedVec::new();
-        //           .get_mut()?
-        self.children.write().unwrap().push(new.clone());
+    /// Makes this a handle to the same scope as `source`, as with
+    /// [Clone::clone], rather than copying `source`'s values into the scope
+    /// this handle points to. Nothing is allocated either way, but this
+    /// leaves the handle untouched if it already points to `source`'s
+    /// scope. Otherwise the old handle is dropped as usual, which can
+    /// trigger [ScopedVec::set_promote_on_drop].
+    fn clone_from(&mut self, source: &Self) {
+        if !Arc::ptr_eq(&self.node, &source.node) {
+            *self = source.clone();
+        }
+    }
+}
+
+impl<T, S> Drop for ScopedVec<T, S> {
+    fn drop(&mut self) {
+        if self.counted
+            && self.node.handles.fetch_sub(1, atomic::Ordering::AcqRel) == 1
+            && self.node.promote_on_drop.load(atomic::Ordering::Acquire)
+        {
+            (self.node.promote)(self);
+        }
+    }
+}
+
+impl<T> ScopedVec<T> {
+    pub fn new() -> Self {
+        Self::with_storage()
+    }
+
+    /// Create a new, empty `ScopedVec` which maintains an exact index of
+    /// the values held by each of its scopes, allowing
+    /// [ScopedVec::contains_local] to run in constant time and
+    /// [ScopedVec::contains] in time proportional to the number of
+    /// scopes rather than the number of values. Scopes created from an
+    /// indexed `ScopedVec` are indexed too.
+    ///
+    /// The index stores a clone of every distinct value held in each
+    /// scope alongside a count of its occurrences, so this roughly
+    /// doubles memory usage for values without any duplicates.
+    pub fn new_indexed() -> Self
+        where T: Eq + Hash + Clone + Send + Sync + 'static
+    {
+        Self::from_local(Local::indexed())
+    }
+
+    /// Create a new, empty `ScopedVec` with room for at least `cap`
+    /// values in the root scope before it has to reallocate. Scopes
+    /// created from it start out empty as usual.
+    pub fn with_capacity(cap: usize) -> Self {
+        let new = Self::new();
+        new.reserve(cap);
         new
     }
 
-    pub fn push(&mut self, val: T) {
-        //        .get_mut()?
-        self.inner.write().unwrap().push(val);
+    /// Create a new, empty `ScopedVec` with room for at least `cap`
+    /// direct children before the list of children has to reallocate.
+    /// This has no effect on the capacity for values.
+    pub fn with_children_capacity(cap: usize) -> Self {
+        let new = Self::new();
+        new.reserve_children(cap);
+        new
     }
 
-    pub fn iter(&self) -> ScopedVecIterator<T> {
-        ScopedVecIterator::new(self)
+    /// Create a new, empty `ScopedVec` which records the thread that
+    /// pushed each value, see [ScopedVec::iter_with_provenance]. Scopes
+    /// created from this one record provenance too.
+    pub fn new_with_provenance() -> Self {
+        Self::from_local(Local::with_provenance())
     }
-}
 
-impl<T: Clone + PartialEq> ScopedVec<T> {
-    pub fn contains(&self, val: &T) -> bool {
-        self.iter().any(|f| *f == *val)
+    /// Build a new tree from its nested representation.
+    pub fn from_nested(nested: NestedVec<T>) -> Self {
+        let new = Self::new();
+        new.extend_from_nested(nested);
+        new
     }
 }
 
-pub struct ScopedVecGuardHolder<'a, T: Clone> {
-    inner: RwLockReadGuard<'a, Vec<T>>,
-    children: RwLockReadGuard<'a, Vec<ScopedVec<T>>>,
-}
+impl<T, S: Storage<T>> ScopedVec<T, S> {
+    /// Create a new, empty `ScopedVec` using `S` to store the values of
+    /// each scope rather than the default `Vec`.
+    ///
+    /// ```
+    /// # use scoped_vec::ScopedVec;
+    /// let root = ScopedVec::<i32, Vec<i32>>::with_storage();
+    /// root.push(3);
+    /// ```
+    pub fn with_storage() -> Self {
+        Self::from_local(Local::default())
+    }
 
-pub struct ScopedVecIterator<'a, T: Clone> {
-    iterator: OwningHandle<Box<ScopedVecGuardHolder<'a, T>>, Box<dyn Iterator<Item = &'a T> + 'a>>,
-}
-impl<'a, T: Clone> ScopedVecIterator<'a, T> {
-    fn new(vec: &'a ScopedVec<T>) -> Self {
+    fn from_local(local: Local<T, S>) -> Self {
         Self {
-            iterator: OwningHandle::new_with_fn(
-                Box::new(ScopedVecGuardHolder {
-                    inner: vec.inner.read().unwrap(),
-                    children: vec.children.read().unwrap()
-                }),
-                |g| {
-                    // the value behind the raw pointer `g` is boxed, so we're safe to dereference
-                    let guards = unsafe { &*g };
+            node: Arc::new(Node {
+                inner: RwLock::new(local),
+                children: RwLock::default(),
+                parent: RwLock::default(),
+                observers: Observers::default(),
+                #[cfg(feature = "tokio")]
+                watchers: RwLock::default(),
+                handles: AtomicUsize::new(1),
+                promote_on_drop: AtomicBool::new(false),
+                promote: Self::promote,
+                #[cfg(feature = "metrics")]
+                metrics_prefix: RwLock::default()
+            }),
+            counted: true
+        }
+    }
+
+    /// Another handle to this scope which isn't counted as one given out
+    /// by the methods creating it, for the tree to hold on to.
+    fn handle(&self) -> Self {
+        Self {
+            node: self.node.clone(),
+            counted: false
+        }
+    }
+
+    fn extend_from_nested(&self, nested: NestedVec<T>) {
+        self.push_all(nested.values);
+
+        for child in nested.children {
+            self.scope().extend_from_nested(child);
+        }
+    }
+
+    /// Create a new, empty scope configured the same way as this one and
+    /// pointing back to it as its parent, without adding it to this
+    /// scope's children.
+    fn new_child(&self) -> Self {
+        let new = Self::from_local(lock::read(&self.node.inner).new_like());
+        *lock::write(&new.node.parent) = Arc::downgrade(&self.node);
+        new
+    }
+
+    /// Stop this handle being counted as one given out by the methods
+    /// creating the scope, without promoting the scope if it was the
+    /// last of those.
+    fn uncounted(mut self) -> Self {
+        if self.counted {
+            self.node.handles.fetch_sub(1, atomic::Ordering::AcqRel);
+            self.counted = false;
+        }
+        self
+    }
+
+    /// Clear the link back to this scope's parent, after it has been
+    /// removed from the parent's children.
+    fn detach(&self) {
+        *lock::write(&self.node.parent) = Weak::new();
+    }
+
+    /// Create a new `ScopedVec` as a child of this one.
+    pub fn scope(&self) -> ScopedVec<T, S> {
+        let new = self.new_child();
+        lock::write(&self.node.children).push(new.handle());
+        new
+    }
 
-                    Box::new(guards.inner.iter()
-                        .chain(
-                            guards.children.iter()
-                                .map(ScopedVec::iter)
-                                .flatten()
-                        )) as Box<dyn Iterator<Item = &'a T>>
+    /// Create a new child scope for each of `seeds`, holding that seed
+    /// as its only value, returning handles to the new scopes in the
+    /// same order.
+    pub fn fan_out<I: IntoIterator<Item = T>>(&self, seeds: I) -> Vec<ScopedVec<T, S>> {
+        seeds.into_iter()
+            .map(|seed| {
+                let child = self.scope();
+                child.push(seed);
+                child
+            })
+            .collect()
+    }
+
+    /// Create a new child scope like [ScopedVec::scope], failing rather
+    /// than waiting if this scope's locks are held by another thread.
+    pub fn try_scope(&self) -> Result<ScopedVec<T, S>, ScopedVecError> {
+        let new = Self::from_local(lock::try_read(&self.node.inner)?.new_like());
+        let mut children = lock::try_write(&self.node.children)?;
+        *lock::write(&new.node.parent) = Arc::downgrade(&self.node);
+        children.push(new.handle());
+        Ok(new)
+    }
+
+    /// Release a scope's spare capacity whenever removing values from it
+    /// leaves it holding fewer than `ratio` times as many values as it
+    /// has capacity for, so trees which churn don't hold on to memory
+    /// from their peak. This applies to this scope, its descendants and
+    /// any scopes created under them later. It's disabled by default,
+    /// and can be disabled again by passing a `ratio` of 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ratio` isn't between 0 and 1.
+    pub fn set_auto_shrink(&self, ratio: f32) {
+        assert!((0.0..=1.0).contains(&ratio), "auto shrink ratio must be between 0 and 1");
+
+        let ratio = if ratio == 0.0 { None } else { Some(ratio) };
+        self.for_each_scope(|_| true, |scope| lock::write(&scope.node.inner).auto_shrink = ratio);
+    }
+
+    /// Returns true if both handles refer to the same scope.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.node, &other.node)
+    }
+
+    /// Returns the number of handles to this scope, including this one
+    /// and the one held by its parent. Useful for finding out what's
+    /// keeping a scope alive, although the count is only advisory as
+    /// other threads can create or drop handles at any time.
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.node)
+    }
+
+    /// Returns the number of weak references to this scope, which are
+    /// held by each of its children to point back at their parent. As
+    /// with [ScopedVec::strong_count], this is only advisory.
+    pub fn weak_count(&self) -> usize {
+        Arc::weak_count(&self.node)
+    }
+
+    /// Returns the scope found by following `path` down from this one,
+    /// where each element is the index of a child within its parent. An
+    /// empty path returns this scope itself.
+    pub fn scope_at_path(&self, path: &[usize]) -> Option<ScopedVec<T, S>> {
+        let mut current = self.clone();
+        for &i in path {
+            let next = lock::read(&current.node.children).get(i)?.clone();
+            current = next;
+        }
+        Some(current)
+    }
+
+    /// Walk this scope and its descendants depth first, calling `op` on
+    /// every scope for which `filter` returns true. No locks are held
+    /// while `filter` or `op` run, so they're free to read or modify the
+    /// scope they're given. A scope's children are looked up after `op`
+    /// has run on it, so children added or removed by `op` are taken
+    /// into account.
+    pub fn for_each_scope<P, F>(&self, mut filter: P, mut op: F)
+        where P: FnMut(&ScopedVec<T, S>) -> bool, F: FnMut(&ScopedVec<T, S>)
+    {
+        let mut stack = vec![self.clone()];
+
+        while let Some(scope) = stack.pop() {
+            if filter(&scope) {
+                op(&scope);
+            }
+
+            stack.extend(scope.child_handles().into_iter().rev());
+        }
+    }
+
+    /// Remove every descendant scope for which `f` returns false, along
+    /// with its own descendants. `f` isn't called on this scope, nor on
+    /// the descendants of a scope that's removed. No locks are held
+    /// while `f` runs.
+    pub fn retain_scopes<F: FnMut(&ScopedVec<T, S>) -> bool>(&self, f: F) {
+        self.retain_scopes_reporting(f);
+    }
+
+    /// Like [ScopedVec::retain_scopes], returning the total number of
+    /// scopes removed, counting each removed scope's descendants.
+    pub fn retain_scopes_reporting<F: FnMut(&ScopedVec<T, S>) -> bool>(&self, mut f: F) -> usize {
+        self.retain_scopes_inner(&mut f)
+    }
+
+    /// Remove every descendant scope for which `f` returns true, along
+    /// with its own descendants, and return handles to the removed
+    /// scopes in depth first order. Each is detached from the tree and
+    /// becomes the root of its own. `f` isn't called on this scope, nor
+    /// on the descendants of a scope that's removed, so a removed
+    /// subtree's matching descendants stay within it rather than being
+    /// returned separately. No locks are held while `f` runs.
+    pub fn drain_scopes_where<F: FnMut(&ScopedVec<T, S>) -> bool>(&self, mut f: F) -> Vec<ScopedVec<T, S>> {
+        let mut drained = Vec::new();
+        self.retain_scopes_inner(&mut |scope: &ScopedVec<T, S>| {
+            let matches = f(scope);
+            if matches {
+                drained.push(scope.clone());
+            }
+            !matches
+        });
+        drained
+    }
+
+    fn retain_scopes_inner<F: FnMut(&ScopedVec<T, S>) -> bool>(&self, f: &mut F) -> usize {
+        let mut removed = 0;
+        let mut rejected = Vec::new();
+
+        for child in self.child_handles() {
+            if f(&child) {
+                removed += child.retain_scopes_inner(f);
+            } else {
+                child.for_each_scope(|_| true, |_| removed += 1);
+                rejected.push(Arc::as_ptr(&child.node));
+            }
+        }
+
+        if !rejected.is_empty() {
+            lock::write(&self.node.children).retain(|child| {
+                let keep = !rejected.contains(&Arc::as_ptr(&child.node));
+                if !keep {
+                    child.detach();
                 }
-            )
+                keep
+            });
+            self.len_changed();
         }
+
+        removed
     }
-}
-impl<'a, T: Clone> Iterator for ScopedVecIterator<'a, T> {
-    type Item = &'a T;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.iterator.next()
+    /// Returns handles to this scope and every descendant for which
+    /// `filter` returns true, in depth first order.
+    pub fn collect_scopes<P>(&self, mut filter: P) -> Vec<ScopedVec<T, S>>
+        where P: FnMut(&ScopedVec<T, S>) -> bool
+    {
+        let mut out = Vec::new();
+        self.for_each_scope(&mut filter, |scope| out.push(scope.clone()));
+        out
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::ScopedVec;
+    /// Returns a handle to the first scope, in depth first order, for
+    /// which `f` returns true. This scope is checked first, and the walk
+    /// stops as soon as a match is found. No locks are held while `f`
+    /// runs.
+    pub fn find_scope<F: FnMut(&ScopedVec<T, S>) -> bool>(&self, mut f: F) -> Option<ScopedVec<T, S>> {
+        let mut stack = vec![self.clone()];
 
-    #[test]
-    fn unscoped_standard() {
-        let mut root = ScopedVec::new();
-        root.push(3);
-        let mut iter = root.iter();
-        assert_eq!(iter.next(), Some(&3));
-        assert_eq!(iter.next(), None);
+        while let Some(scope) = stack.pop() {
+            if f(&scope) {
+                return Some(scope);
+            }
+
+            stack.extend(scope.child_handles().into_iter().rev());
+        }
+
+        None
     }
 
-    #[test]
-    fn scoped_cant_read_root() {
-        let mut root = ScopedVec::new();
-        root.push(3);
+    /// Returns, for this scope and each of its descendants in depth
+    /// first order, the path to the scope relative to this one along
+    /// with the position in [ScopedVec::iter] its first value lands at
+    /// and the number of values it holds directly. The values of a scope
+    /// therefore occupy `start..start + len` of the flattened view.
+    ///
+    /// The offsets are computed up front in a single walk of the tree,
+    /// so they won't reflect any changes made while iterating.
+    pub fn iter_scope_offsets(&self) -> std::vec::IntoIter<(Vec<usize>, usize, usize)> {
+        let mut offsets = Vec::new();
+        let mut start = 0;
 
-        let scoped = root.scope();
-        let mut iter = scoped.iter();
-        assert_eq!(iter.next(), None);
+        self.walk_with_paths(|path, scope| {
+            let len = scope.len();
+            offsets.push((path.to_vec(), start, len));
+            start += len;
+        });
+
+        offsets.into_iter()
     }
 
-    #[test]
-    fn root_can_read_scoped() {
-        let mut root = ScopedVec::new();
-        root.push(3);
+    /// Returns, for this scope and each of its descendants in depth
+    /// first order, the path to the scope relative to this one along
+    /// with how many of the values it holds directly match `f`. Scopes
+    /// without any matches are included with a count of 0.
+    pub fn count_per_scope<F: FnMut(&T) -> bool>(&self, mut f: F) -> Vec<(Vec<usize>, usize)> {
+        let mut counts = Vec::new();
+        self.walk_with_paths(|path, scope| {
+            let count = lock::read(&scope.node.inner).values.iter().filter(|v| f(v)).count();
+            counts.push((path.to_vec(), count));
+        });
+        counts
+    }
 
-        let mut scoped = root.scope();
-        scoped.push(4);
+    /// Returns a hash of the shape of this tree, made up of the number of
+    /// values and children of each scope, without looking at the values
+    /// themselves. Trees with the same shape hash the same, so a change
+    /// in the hash means values or scopes have been added or removed
+    /// somewhere. The hash is only stable within a single build of the
+    /// program.
+    pub fn structural_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.for_each_scope(|_| true, |scope| {
+            lock::read(&scope.node.inner).values.len().hash(&mut hasher);
+            lock::read(&scope.node.children).len().hash(&mut hasher);
+        });
+        hasher.finish()
+    }
 
-        let mut iter = root.iter();
-        assert_eq!(iter.next(), Some(&3));
-        assert_eq!(iter.next(), Some(&4));
-        assert_eq!(iter.next(), None);
+    /// Returns how many scopes, this one and its descendants, hold at
+    /// least one value directly, leaving out those only used to group
+    /// their children.
+    pub fn count_nonempty_scopes(&self) -> usize {
+        let mut count = 0;
+        self.for_each_scope(|scope| !lock::read(&scope.node.inner).values.is_empty(), |_| count += 1);
+        count
     }
 
-    #[test]
-    fn root_can_read_nested_scoped() {
-        let mut root = ScopedVec::new();
-        root.push(3);
+    /// Replace the scope found by following `path` down from this one
+    /// with `new_subtree`, returning the handle to the scope that was
+    /// replaced. The replaced scope is detached from the tree, keeping
+    /// its values and children. Returns `Ok(None)` and leaves the tree
+    /// as is if `new_subtree` is already at `path`.
+    ///
+    /// `new_subtree` has to be a root of its own, it's rejected with
+    /// [ScopedVecError::Attached] if it's still attached to a parent,
+    /// see [ScopedVec::drain_scopes_where] for one way of detaching it.
+    /// If it's the scope it would be placed under or one of that scope's
+    /// ancestors, the tree would contain itself, so it's rejected with
+    /// [ScopedVecError::Cycle].
+    ///
+    /// An empty path would replace this scope itself, which can't be
+    /// done through a handle to it, so is rejected with
+    /// [ScopedVecError::EmptyPath].
+    pub fn replace_subtree_at_path(&self, path: &[usize], new_subtree: ScopedVec<T, S>)
+        -> Result<Option<ScopedVec<T, S>>, ScopedVecError>
+    {
+        let (&index, parent_path) = path.split_last().ok_or(ScopedVecError::EmptyPath)?;
+        let invalid = |depth| ScopedVecError::InvalidPath { path: path.to_vec(), depth };
 
-        let mut scoped = root.scope();
-        scoped.push(4);
+        let mut parent = self.clone();
+        for (depth, &i) in parent_path.iter().enumerate() {
+            let next = lock::read(&parent.node.children).get(i).cloned().ok_or_else(|| invalid(depth))?;
+            parent = next;
+        }
 
-        let mut nested_scoped = scoped.scope();
-        nested_scoped.push(5);
+        let old = {
+            let mut children = lock::write(&parent.node.children);
+            let slot = children.get_mut(index).ok_or_else(|| invalid(path.len() - 1))?;
+            if slot.ptr_eq(&new_subtree) {
+                return Ok(None);
+            }
+            if lock::read(&new_subtree.node.parent).upgrade().is_some() {
+                return Err(ScopedVecError::Attached);
+            }
+            if new_subtree.is_ancestor_or_self_of(&parent) {
+                return Err(ScopedVecError::Cycle);
+            }
+            std::mem::replace(slot, new_subtree.handle())
+        };
 
-        let mut iter = root.iter();
-        assert_eq!(iter.next(), Some(&3));
-        assert_eq!(iter.next(), Some(&4));
-        assert_eq!(iter.next(), Some(&5));
-        assert_eq!(iter.next(), None);
+        *lock::write(&new_subtree.node.parent) = Arc::downgrade(&parent.node);
+        old.detach();
+        parent.len_changed();
+
+        Ok(Some(old))
     }
 
-    #[test]
-    fn scoped_can_read_nested_scoped() {
-        let mut root = ScopedVec::new();
-        root.push(3);
+    /// Whether `scope` is this scope or one of its descendants, found by
+    /// following `scope`'s parent links up.
+    fn is_ancestor_or_self_of(&self, scope: &ScopedVec<T, S>) -> bool {
+        let mut current = Some(scope.node.clone());
+        while let Some(node) = current {
+            if Arc::ptr_eq(&node, &self.node) {
+                return true;
+            }
+            current = lock::read(&node.parent).upgrade();
+        }
+        false
+    }
 
-        let mut scoped = root.scope();
-        scoped.push(4);
+    /// Returns the path of child indexes leading from the root of the
+    /// tree down to this scope, such that passing it to
+    /// [ScopedVec::scope_at_path] on the root returns this scope. The
+    /// root itself has an empty path.
+    ///
+    /// Scopes only hold weak links to their parents, so if an ancestor
+    /// has been dropped the path is relative to the topmost ancestor
+    /// that's still alive. Scopes which have been removed from their
+    /// parent, such as by [ScopedVec::collapse_below], are roots of
+    /// their own.
+    pub fn path_from_root(&self) -> Vec<usize> {
+        let mut path = Vec::new();
+        let mut current = self.node.clone();
 
-        let mut nested_scoped = scoped.scope();
-        nested_scoped.push(5);
+        loop {
+            let parent = lock::read(&current.parent).upgrade();
+            let parent = match parent {
+                Some(parent) => parent,
+                None => break,
+            };
 
-        let mut iter = scoped.iter();
-        assert_eq!(iter.next(), Some(&4));
-        assert_eq!(iter.next(), Some(&5));
-        assert_eq!(iter.next(), None);
+            let index = lock::read(&parent.children)
+                .iter()
+                .position(|child| Arc::ptr_eq(&child.node, &current));
+            match index {
+                Some(index) => path.push(index),
+                None => break,
+            }
+
+            current = parent;
+        }
+
+        path.reverse();
+        path
     }
 
-    #[test]
-    fn nested_scoped_cant_read_backwards() {
-        let mut root = ScopedVec::new();
-        root.push(3);
+    /// Create a handle which pushes each thread's values into a child
+    /// scope of this one dedicated to that thread. See [ThreadScoped].
+    pub fn per_thread(&self) -> ThreadScoped<T, S> {
+        ThreadScoped::new(self.clone())
+    }
 
-        let mut scoped = root.scope();
-        scoped.push(4);
+    /// Create `n` new child scopes of this one at once, returning their
+    /// handles in the order they were added. This is useful for handing
+    /// out a scope per worker, see [ScopedVec::scatter_gather] for a
+    /// helper that spawns the workers too.
+    pub fn split_for_parallel(&self, n: usize) -> Vec<ScopedVec<T, S>> {
+        let new: Vec<_> = (0..n).map(|_| self.new_child()).collect();
+        lock::write(&self.node.children).extend(new.iter().map(ScopedVec::handle));
+        new
+    }
 
-        let mut nested_scoped = scoped.scope();
-        nested_scoped.push(5);
+    /// Spawn `n` scoped threads, handing each of them its index and a
+    /// new child scope of this one, and wait for all of them to finish.
+    /// Returns the results of `f` in the order of the indices.
+    ///
+    /// The values pushed by each of the workers can be read back from
+    /// this scope once they've all finished:
+    ///
+    /// ```
+    /// # use scoped_vec::ScopedVec;
+    /// let root = ScopedVec::new();
+    ///
+    /// let pushed = root.scatter_gather(4, |i, scope| {
+    ///     scope.push(i);
+    ///     1
+    /// });
+    /// assert_eq!(pushed, vec![1, 1, 1, 1]);
+    ///
+    /// let values: Vec<_> = root.iter().copied().collect();
+    /// assert_eq!(values, vec![0, 1, 2, 3]);
+    /// ```
+    pub fn scatter_gather<R, F>(&self, n: usize, f: F) -> Vec<R>
+        where T: Send + Sync, S: Send + Sync, R: Send, F: Fn(usize, ScopedVec<T, S>) -> R + Sync
+    {
+        let scopes = self.split_for_parallel(n);
+        let f = &f;
 
-        let mut iter = nested_scoped.iter();
-        assert_eq!(iter.next(), Some(&5));
-        assert_eq!(iter.next(), None);
+        std::thread::scope(|s| {
+            let handles: Vec<_> = scopes.into_iter()
+                .enumerate()
+                .map(|(i, scope)| s.spawn(move || f(i, scope)))
+                .collect();
+
+            handles.into_iter()
+                .map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                .collect()
+        })
     }
 
-    #[test]
-    fn can_drop_scopes() {
-        let mut root = ScopedVec::new();
-        root.push(3);
+    /// Swap the positions of two of this scope's direct children,
+    /// changing the order their values are visited in by
+    /// [ScopedVec::iter].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `i` or `j` are out of bounds.
+    pub fn swap_children(&self, i: usize, j: usize) {
+        lock::write(&self.node.children).swap(i, j);
+    }
 
-        let mut scoped = root.scope();
-        scoped.push(4);
+    /// Reserve room for at least `additional` more values to be pushed
+    /// directly into this scope, so a burst of pushes of a known size
+    /// doesn't reallocate along the way.
+    pub fn reserve(&self, additional: usize) {
+        Self::update_local(lock::write(&self.node.inner), |local| local.reserve(additional));
+    }
 
-        drop(root);
+    /// Release as much of the spare capacity for values held directly
+    /// by this scope as possible. See [ScopedVec::set_auto_shrink] to do
+    /// this automatically as values are removed.
+    pub fn shrink_to_fit(&self) {
+        Self::update_local(lock::write(&self.node.inner), Local::shrink_to_fit);
+    }
 
-        let mut nested_scoped = scoped.scope();
-        nested_scoped.push(5);
+    /// The number of values this scope can hold directly before it has to
+    /// reallocate.
+    pub fn capacity(&self) -> usize {
+        lock::read(&self.node.inner).values.capacity()
+    }
 
-        {
-            let mut iter = scoped.iter();
-            assert_eq!(iter.next(), Some(&4));
-            assert_eq!(iter.next(), Some(&5));
-            assert_eq!(iter.next(), None);
+    /// Reserve room for at least `additional` more direct children of
+    /// this scope, avoiding repeated reallocation when fanning out into
+    /// many scopes.
+    pub fn reserve_children(&self, additional: usize) {
+        lock::write(&self.node.children).reserve(additional);
+    }
+
+    /// The number of direct children this scope can hold before its
+    /// list of children has to reallocate.
+    pub fn children_capacity(&self) -> usize {
+        lock::read(&self.node.children).capacity()
+    }
+
+    /// Push a value onto the end of this scope, after any values pushed
+    /// into it before. The value is visible through every handle to this
+    /// scope and to each of its ancestors.
+    ///
+    /// This takes the scope's write lock, so it waits for the scope's
+    /// iterators to be dropped, and deadlocks if one is alive on this
+    /// thread. See [ScopedVec#deadlocks].
+    pub fn push(&self, val: T) {
+        self.write_local(|local| {
+            local.touch();
+            self.notify_push(&val);
+            local.push(val);
+        });
+    }
+
+    /// Returns the value at `index` among those pushed directly into this
+    /// scope, or `None` if it's out of bounds. The scope stays read
+    /// locked while the returned guard is held, see
+    /// [ScopedVec::get_cloned] for a copy which doesn't hold the lock.
+    pub fn get(&self, index: usize) -> Option<ElementGuard<'_, T, S>> {
+        let local = lock::read(&self.node.inner);
+        if index < local.values.len() {
+            Some(ElementGuard { local, index, _held: lock::Held::new(&self.node.inner) })
+        } else {
+            None
         }
+    }
 
-        drop(scoped);
+    /// Returns a clone of the value at `index` among those pushed directly
+    /// into this scope, or `None` if it's out of bounds.
+    pub fn get_cloned(&self, index: usize) -> Option<T>
+        where T: Clone
+    {
+        lock::read(&self.node.inner).values.get(index).cloned()
+    }
 
-        {
-            let mut iter = nested_scoped.iter();
-            assert_eq!(iter.next(), Some(&5));
-            assert_eq!(iter.next(), None);
+    /// Swap the values at `a` and `b` among those pushed directly into
+    /// this scope, as with [slice::swap].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `a` or `b` are out of bounds, in which case nothing is
+    /// moved and the scope remains usable.
+    pub fn swap(&self, a: usize, b: usize) {
+        Self::update_local(lock::write(&self.node.inner), |local| local.swap(a, b));
+    }
+
+    /// Returns the first value pushed directly into this scope, ignoring
+    /// its children, holding the scope's read lock as [ScopedVec::get]
+    /// does.
+    pub fn first(&self) -> Option<ElementGuard<'_, T, S>> {
+        self.get(0)
+    }
+
+    /// Returns the last value pushed directly into this scope, ignoring
+    /// its children, holding the scope's read lock as [ScopedVec::get]
+    /// does.
+    pub fn last(&self) -> Option<ElementGuard<'_, T, S>> {
+        let local = lock::read(&self.node.inner);
+        let index = local.values.len().checked_sub(1)?;
+        Some(ElementGuard { local, index, _held: lock::Held::new(&self.node.inner) })
+    }
+
+    /// Returns a clone of the last value yielded by [ScopedVec::iter], ie.
+    /// the last value of the last descendant holding any. Only the scopes
+    /// after that one are visited, rather than the whole tree. The value
+    /// may live in a descendant which isn't otherwise borrowed, so it's
+    /// cloned rather than returned behind a guard.
+    pub fn last_deep(&self) -> Option<T>
+        where T: Clone
+    {
+        self.child_handles().iter().rev()
+            .find_map(ScopedVec::last_deep)
+            .or_else(|| self.last().map(|val| val.clone()))
+    }
+
+    /// Insert `val` at `index` among the values held directly by this
+    /// scope, shifting those after it up, as with [Vec::insert]. The value
+    /// is passed on to [ScopedVec::pipe_to] in the same way as a push.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than the number of values held
+    /// directly by this scope, in which case nothing is inserted and the
+    /// scope remains usable.
+    pub fn insert(&self, index: usize, val: T) {
+        self.write_local(|local| {
+            let len = local.values.len();
+            assert!(index <= len, "insertion index (is {}) should be <= len (is {})", index, len);
+            local.touch();
+            self.notify_push(&val);
+            local.insert(index, val);
+        });
+    }
+
+    /// Remove and return the value most recently pushed directly into
+    /// this scope, leaving its children untouched. Returns `None` if this
+    /// scope holds no values of its own.
+    pub fn pop(&self) -> Option<T> {
+        self.write_local(Local::pop)
+    }
+
+    /// Remove and return the value at `index` among those pushed directly
+    /// into this scope, shifting the values after it down, as with
+    /// [Vec::remove]. Children are left untouched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds, in which case nothing is
+    /// removed and the scope remains usable.
+    pub fn remove(&self, index: usize) -> T {
+        self.write_local(|local| {
+            let len = local.values.len();
+            assert!(index < len, "removal index (is {}) should be < len (is {})", index, len);
+            local.remove(index)
+        })
+    }
+
+    /// Remove and return the value at `index` among those pushed directly
+    /// into this scope, moving the scope's last value into its place, as
+    /// with [Vec::swap_remove]. This doesn't preserve the order of the
+    /// scope's values, but takes constant time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds, in which case nothing is
+    /// removed and the scope remains usable.
+    pub fn swap_remove(&self, index: usize) -> T {
+        self.write_local(|local| {
+            let len = local.values.len();
+            assert!(index < len, "swap_remove index (is {}) should be < len (is {})", index, len);
+            local.swap_remove(index)
+        })
+    }
+
+    /// Push every value yielded by `vals` onto this scope, in order,
+    /// taking the scope's write lock once for the whole batch rather than
+    /// once per value. Each value is passed on to [ScopedVec::pipe_to] as
+    /// it would be by [ScopedVec::push].
+    ///
+    /// `vals` is consumed while the lock is held, so it mustn't read from
+    /// or write to this scope. If it panics, the values pushed before the
+    /// panic are kept.
+    pub fn push_all<I: IntoIterator<Item = T>>(&self, vals: I) {
+        self.write_local(|local| {
+            for val in vals {
+                local.touch();
+                self.notify_push(&val);
+                local.push(val);
+            }
+        });
+    }
+
+    /// Push a clone of each of `vals` onto this scope, taking the scope's
+    /// write lock once, as with [ScopedVec::push_all].
+    pub fn extend_from_slice(&self, vals: &[T])
+        where T: Clone
+    {
+        self.push_all(vals.iter().cloned());
+    }
+
+    /// Iterate over the values of this scope and its descendants depth
+    /// first, a scope's own values before those of its children.
+    ///
+    /// The iterator holds the read locks of every scope it has reached
+    /// until it's dropped, so modifying any of them from the same thread
+    /// while iterating will deadlock. See [ScopedVec#deadlocks].
+    pub fn iter(&self) -> ScopedVecIterator<'_, T, S> {
+        ScopedVecIterator::new(self)
+    }
+
+    /// Push a value onto this scope without waiting for the scope's lock,
+    /// returning the value back if it's held by another thread or has
+    /// been poisoned.
+    pub fn try_push(&self, val: T) -> Result<(), TryPushError<T>> {
+        match lock::try_write(&self.node.inner) {
+            Ok(local) => {
+                Self::update_local(local, |local| {
+                    local.touch();
+                    self.notify_push(&val);
+                    local.push(val);
+                });
+                self.len_changed();
+                Ok(())
+            }
+            Err(error) => Err(TryPushError { value: val, error }),
         }
     }
 
-    #[test]
-    fn diverged_scopes_can_be_read() {
-        let mut root = ScopedVec::new();
-        root.push(3);
+    /// Iterate over the values of this tree like [ScopedVec::iter],
+    /// failing rather than waiting if this scope's locks are held by
+    /// another thread. Only this scope's locks are tried up front, the
+    /// locks of its descendants are taken as they're reached, blocking
+    /// as [ScopedVec::iter] does.
+    pub fn try_iter(&self) -> Result<ScopedVecIterator<'_, T, S>, ScopedVecError> {
+        let inner = lock::try_read(&self.node.inner)?;
+        let children = lock::try_read(&self.node.children)?;
+        let guards = ScopedVecGuardHolder::new(self, inner, children);
+        Ok(ScopedVecIterator::from_guards(guards, None))
+    }
 
-        let mut scoped = root.scope();
-        scoped.push(4);
+    /// Iterate over the values of this tree like [ScopedVec::iter],
+    /// waiting at most `timeout` in total for this scope's locks and
+    /// failing with [ScopedVecError::Timeout] if they can't be taken in
+    /// time. As with [ScopedVec::try_iter], the locks of descendants are
+    /// taken as they're reached and aren't covered by the timeout.
+    ///
+    /// `std`'s locks can't be waited on with a timeout, so unless the
+    /// `parking_lot` feature is enabled the locks are polled until the
+    /// deadline rather than the thread being woken as soon as they're
+    /// released.
+    pub fn iter_timeout(&self, timeout: Duration) -> Result<ScopedVecIterator<'_, T, S>, ScopedVecError> {
+        let deadline = Instant::now() + timeout;
+        let inner = lock::read_until(&self.node.inner, deadline)?;
+        let children = lock::read_until(&self.node.children, deadline)?;
+        let guards = ScopedVecGuardHolder::new(self, inner, children);
+        Ok(ScopedVecIterator::from_guards(guards, None))
+    }
 
-        let mut nested_scoped1 = scoped.scope();
-        nested_scoped1.push(5);
+    /// Iterate over the values of this tree in the same order as
+    /// [ScopedVec::iter], alongside the thread which pushed each of them.
+    /// Only trees created using [ScopedVec::new_with_provenance] record
+    /// provenance, for any other tree this yields nothing.
+    pub fn iter_with_provenance(&self) -> ProvenanceIterator<'_, T, S> {
+        ProvenanceIterator::new(self)
+    }
 
-        let mut nested_scoped2 = scoped.scope();
-        nested_scoped2.push(6);
+    /// Iterate over the values of this tree bottom up. Each of a scope's
+    /// children is iterated this way in turn, in the order they were
+    /// created, before the scope's own values, so every value comes after
+    /// all of the values of the scopes nested inside the scope holding it.
+    /// [ScopedVec::iter] instead yields a scope's own values first.
+    pub fn iter_postorder(&self) -> ScopedVecIterator<'_, T, S> {
+        ScopedVecIterator::postorder(self)
+    }
 
-        let mut iter = root.iter();
-        assert_eq!(iter.next(), Some(&3));
-        assert_eq!(iter.next(), Some(&4));
-        assert_eq!(iter.next(), Some(&5));
-        assert_eq!(iter.next(), Some(&6));
-        assert_eq!(iter.next(), None);
+    /// Walk this scope and its descendants depth first, yielding an
+    /// event as each scope is entered and left along with each of the
+    /// values in between. A scope's values come straight after it's
+    /// entered, before any of its children, so skipping everything but
+    /// [IterEvent::Value]s gives the same values as [ScopedVec::iter].
+    pub fn iter_events(&self) -> EventIterator<'_, T, S> {
+        EventIterator::new(self, 0, None)
     }
 
-    #[test]
-    fn diverged_adjacent_scopes_cant_interact() {
-        let mut root = ScopedVec::new();
-        root.push(3);
+    /// Iterate over every `step`-th value of the flattened view, ie. the
+    /// values at positions `0`, `step`, `2 * step` and so on of
+    /// [ScopedVec::iter]. Useful for a cheap, downsampled view of a
+    /// large tree.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `step` is 0.
+    pub fn iter_sample(&self, step: usize) -> std::iter::StepBy<ScopedVecIterator<'_, T, S>> {
+        assert!(step != 0, "iter_sample step must be non-zero");
+        self.iter().step_by(step)
+    }
 
-        let mut scoped1 = root.scope();
-        scoped1.push(4);
+    /// Iterate over the values in this scope, only descending into a
+    /// child scope if `should_descend` returns true for it. This allows
+    /// entire branches of the tree to be skipped over without visiting
+    /// any of their values.
+    ///
+    /// `should_descend` is called lazily as the iterator reaches each
+    /// child, while the parent's read locks are held. Only read locks
+    /// are held, so a panic from `should_descend` won't poison them.
+    pub fn iter_pruned<'a, F>(&'a self, should_descend: F) -> ScopedVecIterator<'a, T, S>
+        where F: FnMut(&ScopedVec<T, S>) -> bool + 'a
+    {
+        ScopedVecIterator::with_filter(self, Some(Rc::new(RefCell::new(should_descend))))
+    }
 
-        let mut scoped2 = root.scope();
-        scoped2.push(5);
+    /// Call `f` on every value in this scope and its descendants, in the
+    /// order given by [ScopedVec::iter], allowing them to be modified in
+    /// place.
+    ///
+    /// Scopes are visited one at a time, parent before children, holding
+    /// only the write lock of the scope whose values are being passed to
+    /// `f`. Other scopes can be read and written while `f` runs, but
+    /// calling back into the scope being visited from `f` will deadlock.
+    pub fn for_each_mut<F: FnMut(&mut T)>(&self, mut f: F) {
+        self.for_each_scope(|_| true, |scope| scope.write_local(|local| local.for_each_mut(&mut f)));
+    }
 
-        let mut iter = scoped1.iter();
-        assert_eq!(iter.next(), Some(&4));
-        assert_eq!(iter.next(), None);
+    /// Call `f` on the value at position `index` of [ScopedVec::iter],
+    /// returning false without calling it if there's no such value.
+    ///
+    /// The scope holding the value is found by reading the length of each
+    /// scope in turn, then `f` is run while holding only that scope's
+    /// write lock, after checking the value is still there. Values added
+    /// to or removed from earlier scopes in the meantime can shift which
+    /// value `index` refers to, but `f` never sees a value another
+    /// thread is part way through updating.
+    pub fn update_at<F: FnOnce(&mut T)>(&self, index: usize, f: F) -> bool {
+        let mut remaining = index;
+        let mut stack = vec![self.clone()];
 
-        let mut iter = scoped2.iter();
-        assert_eq!(iter.next(), Some(&5));
-        assert_eq!(iter.next(), None);
+        while let Some(scope) = stack.pop() {
+            let len = lock::read(&scope.node.inner).values.len();
+            if remaining < len {
+                return Self::update_local(lock::write(&scope.node.inner), |local| {
+                    if remaining < local.values.len() {
+                        local.update(remaining, f);
+                        true
+                    } else {
+                        false
+                    }
+                });
+            }
+
+            remaining -= len;
+            stack.extend(scope.child_handles().into_iter().rev());
+        }
+
+        false
+    }
+
+    /// Replace every value in this scope and its descendants with the
+    /// result of passing it to `f`, for transformations which consume a
+    /// value rather than modify it in place. Each scope's write lock is
+    /// taken once, as with [ScopedVec::for_each_mut]. If `f` panics, the
+    /// values in the scope being visited which hadn't been replaced yet
+    /// are dropped.
+    pub fn replace_all<F: FnMut(T) -> T>(&self, mut f: F) {
+        self.for_each_scope(|_| true, |scope| scope.write_local(|local| local.replace_all(&mut f)));
+    }
+
+    /// Keep only the last `n` values held directly by this scope and by
+    /// each of its descendants, bounding every scope independently
+    /// rather than the tree as a whole.
+    pub fn retain_recent_local(&self, n: usize) {
+        self.for_each_scope(|_| true, |scope| scope.write_local(|local| {
+            let evict = local.values.len().saturating_sub(n);
+            let mut seen = 0;
+            local.retain(|_| {
+                seen += 1;
+                seen > evict
+            });
+        }));
+    }
+
+    /// Retain only the values in this scope for which `f` returns true,
+    /// without touching any of its children.
+    ///
+    /// If `f` panics no values are removed, and the panic is resumed
+    /// once the scope has been unlocked so the tree remains usable from
+    /// other handles.
+    pub fn retain_local<F: FnMut(&T) -> bool>(&self, f: F) {
+        self.write_local(|local| local.retain(f));
+    }
+
+    /// Retain only the values in this scope for which `f` returns true,
+    /// named after [Vec::retain]. The same as [ScopedVec::retain_local],
+    /// see [ScopedVec::retain_deep] to filter descendants as well.
+    pub fn retain<F: FnMut(&T) -> bool>(&self, f: F) {
+        self.retain_local(f);
+    }
+
+    /// Retain only the values in this scope and all of its descendants
+    /// for which `f` returns true, visiting the values in the order
+    /// given by [ScopedVec::iter]. Scopes are filtered one at a time
+    /// under their own write lock, and are kept even if they end up
+    /// empty.
+    ///
+    /// If `f` panics, nothing is removed from the scope being filtered
+    /// at the time, although scopes filtered before it stay filtered.
+    pub fn retain_deep<F: FnMut(&T) -> bool>(&self, mut f: F) {
+        self.for_each_scope(|_| true, |scope| scope.retain_local(&mut f));
+    }
+
+    /// Remove every value in this scope and its descendants for which
+    /// `same(value, kept)` returns true against a value kept before it,
+    /// in the order given by [ScopedVec::iter], so only the first of
+    /// each group of duplicates is kept wherever they are in the tree.
+    /// Scopes are kept even if they end up empty.
+    ///
+    /// Scopes are deduplicated one at a time, parents before children,
+    /// each under its own write lock while the scopes before it are read
+    /// locked. As elsewhere, when more than one scope is locked at once
+    /// the locks are taken in address order, so this can't deadlock with
+    /// [ScopedVec::append] or comparisons between scopes. Each scope is
+    /// checked against the values the scopes before it hold at the time
+    /// it's reached. `same` is called with those locks held, so it
+    /// mustn't modify this tree or read the scope being deduplicated.
+    /// See [ScopedVec#deadlocks].
+    ///
+    /// If `same` panics, the scope being deduplicated at the time is left
+    /// as it was, although those before it stay deduplicated.
+    pub fn dedup_by<F: FnMut(&T, &T) -> bool>(&self, mut same: F) {
+        let scopes = self.collect_scopes(|_| true);
+
+        for (i, scope) in scopes.iter().enumerate() {
+            let mut order: Vec<_> = (0..=i).collect();
+            order.sort_by_key(|&j| Arc::as_ptr(&scopes[j].node));
+
+            let mut earlier = Vec::with_capacity(i);
+            let mut current = None;
+            for j in order {
+                if j == i {
+                    current = Some(lock::write(&scope.node.inner));
+                } else {
+                    earlier.push((j, lock::read(&scopes[j].node.inner)));
+                }
+            }
+            earlier.sort_by_key(|(j, _)| *j);
+
+            let current = current.expect("the current scope is always locked");
+            Self::update_local(current, |local| {
+                let values = local.values.as_slice();
+                let mut keep = Vec::with_capacity(values.len());
+                for (k, val) in values.iter().enumerate() {
+                    let duplicate = earlier.iter().flat_map(|(_, scope)| scope.values.iter())
+                        .chain(values[..k].iter().zip(&keep).filter(|(_, keep)| **keep).map(|(kept, _)| kept))
+                        .any(|kept| same(val, kept));
+                    keep.push(!duplicate);
+                }
+
+                let mut keep = keep.into_iter();
+                local.retain(|_| keep.next().unwrap());
+            });
+            drop(earlier);
+
+            scope.len_changed();
+        }
+    }
+
+    /// Returns the first non-`None` result of calling `f` on the values
+    /// of this scope and its children, in the order they'd be returned
+    /// by [ScopedVec::iter].
+    pub fn find_map<U, F: FnMut(&T) -> Option<U>>(&self, f: F) -> Option<U> {
+        self.iter().find_map(f)
+    }
+
+    /// Count the values of this scope and its children into buckets
+    /// keyed by the result of calling `key` on each of them.
+    pub fn histogram<K: Eq + Hash, F: FnMut(&T) -> K>(&self, mut key: F) -> HashMap<K, usize> {
+        let mut buckets = HashMap::new();
+        for val in self.iter() {
+            *buckets.entry(key(val)).or_insert(0) += 1;
+        }
+        buckets
+    }
+
+    /// Fold over the values of this scope and its children, in the order
+    /// they'd be returned by [ScopedVec::iter], stopping at and
+    /// returning the first error returned by `f`.
+    pub fn try_fold<B, E, F: FnMut(B, &T) -> Result<B, E>>(&self, init: B, f: F) -> Result<B, E> {
+        self.iter().try_fold(init, f)
+    }
+
+    /// Build a new, detached tree with the same shape as this one, with
+    /// each value converted using `f`.
+    pub fn map<U, F: FnMut(&T) -> U>(&self, mut f: F) -> ScopedVec<U> {
+        match self.try_map(|val| Ok::<_, Infallible>(f(val))) {
+            Ok(mapped) => mapped,
+            Err(e) => match e.error {},
+        }
+    }
+
+    /// Build a new, detached tree with the same shape as this one, with
+    /// each value converted using `f`. If `f` fails for any of the
+    /// values, the error is returned along with the location of the
+    /// value and the partially built tree is dropped.
+    pub fn try_map<U, E, F>(&self, mut f: F) -> Result<ScopedVec<U>, MapError<E>>
+        where F: FnMut(&T) -> Result<U, E>
+    {
+        let out = ScopedVec::new();
+        self.try_map_into(&out, &mut Vec::new(), &mut f)?;
+        Ok(out)
+    }
+
+    fn try_map_into<U, E, F>(&self, out: &ScopedVec<U>, path: &mut Vec<usize>, f: &mut F) -> Result<(), MapError<E>>
+        where F: FnMut(&T) -> Result<U, E>
+    {
+        for (index, val) in lock::read(&self.node.inner).values.iter().enumerate() {
+            let mapped = f(val).map_err(|error| MapError { error, path: path.clone(), index })?;
+            out.push(mapped);
+        }
+
+        for (i, child) in self.child_handles().iter().enumerate() {
+            path.push(i);
+            child.try_map_into(&out.scope(), path, f)?;
+            path.pop();
+        }
+
+        Ok(())
+    }
+
+    /// Build a new, detached tree by combining the values of this tree
+    /// with those at the same positions in `other` using `f`. Both trees
+    /// must have the same shape, with the same number of values and
+    /// children in every scope, otherwise the first scope where they
+    /// diverge is returned as an error.
+    ///
+    /// The trees are walked in lockstep, only locking the pair of scopes
+    /// currently being combined.
+    pub fn zip_with<U, US, V, F>(&self, other: &ScopedVec<U, US>, mut f: F) -> Result<ScopedVec<V>, StructureMismatch>
+        where US: Storage<U>, F: FnMut(&T, &U) -> V
+    {
+        let out = ScopedVec::new();
+        self.zip_into(other, Some(&out), &mut Vec::new(), &mut f)?;
+        Ok(out)
+    }
+
+    /// Pair up clones of the values of this tree with those at the same
+    /// positions in `other`, in the order given by [ScopedVec::iter].
+    /// Both trees must have the same shape, otherwise the first scope
+    /// where they diverge is returned as an error, as with
+    /// [ScopedVec::zip_with].
+    pub fn zip<U, US>(&self, other: &ScopedVec<U, US>) -> Result<Vec<(T, U)>, StructureMismatch>
+        where T: Clone, U: Clone, US: Storage<U>
+    {
+        Ok(self.zip_with(other, |l, r| (l.clone(), r.clone()))?.into_iter().collect())
+    }
+
+    /// Check if `other` has the same shape as this tree, with the same
+    /// number of values and children in every scope. See
+    /// [ScopedVec::zip_with].
+    pub fn same_structure<U, US: Storage<U>>(&self, other: &ScopedVec<U, US>) -> bool {
+        self.zip_into::<_, _, (), _>(other, None, &mut Vec::new(), &mut |_, _| ()).is_ok()
+    }
+
+    fn zip_into<U, US, V, F>(&self, other: &ScopedVec<U, US>, out: Option<&ScopedVec<V>>, path: &mut Vec<usize>, f: &mut F)
+        -> Result<(), StructureMismatch>
+        where US: Storage<U>, F: FnMut(&T, &U) -> V
+    {
+        let mismatch = |kind| StructureMismatch { path: path.clone(), kind };
+
+        {
+            let left = lock::read(&self.node.inner);
+            let right = lock::read(&other.node.inner);
+
+            let (left_len, right_len) = (left.values.len(), right.values.len());
+            if left_len != right_len {
+                return Err(mismatch(MismatchKind::LocalLength { left: left_len, right: right_len }));
+            }
+
+            if let Some(out) = out {
+                for (l, r) in left.values.iter().zip(right.values.iter()) {
+                    out.push(f(l, r));
+                }
+            }
+        }
+
+        let left = self.child_handles();
+        let right = other.child_handles();
+        if left.len() != right.len() {
+            return Err(mismatch(MismatchKind::ChildCount { left: left.len(), right: right.len() }));
+        }
+
+        for (i, (l, r)) in left.iter().zip(&right).enumerate() {
+            path.push(i);
+            l.zip_into(r, out.map(ScopedVec::scope).as_ref(), path, f)?;
+            path.pop();
+        }
+
+        Ok(())
+    }
+
+    /// Remove and return the values of every leaf scope in this tree, in
+    /// the order they'd be returned by [ScopedVec::iter]. A leaf is a
+    /// scope without any children, so values held by scopes with
+    /// children are left in place - if this scope has no children then
+    /// it's a leaf itself and its own values are drained.
+    pub fn drain_leaves(&self) -> Vec<T> {
+        let mut out = Vec::new();
+        self.drain_leaves_into(&mut out);
+        out
+    }
+
+    fn drain_leaves_into(&self, out: &mut Vec<T>) {
+        let children = self.child_handles();
+
+        if children.is_empty() {
+            self.write_local(|local| local.take_all_into(out));
+        } else {
+            for child in &children {
+                child.drain_leaves_into(out);
+            }
+        }
+    }
+
+    /// Move the values out of this scope and its descendants, in the
+    /// order given by [ScopedVec::iter], yielding them in batches of
+    /// `batch_size` with only the last batch possibly being smaller. The
+    /// scopes themselves are left in place.
+    ///
+    /// The tree is drained as the iterator goes: each scope is emptied
+    /// as the iterator reaches it, so stopping part way through leaves
+    /// the scopes it hasn't reached untouched. Values pushed into a scope
+    /// after it's been reached are left in the tree.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch_size` is 0.
+    pub fn drain_batched(&self, batch_size: usize) -> DrainBatched<T, S> {
+        DrainBatched::new(self, batch_size)
+    }
+
+    /// Move every value out of this scope and its descendants, leaving
+    /// each scope empty but still attached so its handles carry on
+    /// collecting values. The values are returned alongside the path of
+    /// the scope they came from, relative to this one, in depth first
+    /// order.
+    ///
+    /// Each scope is emptied under its own write lock, so a value pushed
+    /// concurrently is either returned here or left in the scope it was
+    /// pushed to, never lost.
+    pub fn take_tree(&self) -> Vec<(Vec<usize>, Vec<T>)> {
+        let mut out = Vec::new();
+        self.walk_with_paths(|path, scope| {
+            let mut values = Vec::new();
+            scope.write_local(|local| local.take_all_into(&mut values));
+            out.push((path.to_vec(), values));
+        });
+        out
+    }
+
+    /// Remove the values in `range` from those pushed directly into this
+    /// scope, as with [Vec::drain], returning them in order. The values
+    /// are moved out while holding the scope's write lock, which is
+    /// released before this returns, so the scope can be used freely
+    /// while the returned iterator is consumed. Children are left
+    /// untouched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the start of `range` is after its end or its end is
+    /// past the number of values held directly by this scope, in which
+    /// case nothing is removed and the scope remains usable.
+    pub fn drain<R: RangeBounds<usize>>(&self, range: R) -> std::vec::IntoIter<T> {
+        let mut drained = Vec::new();
+        self.write_local(|local| {
+            let len = local.values.len();
+            let start = match range.start_bound() {
+                Bound::Included(&start) => start,
+                Bound::Excluded(&start) => start.checked_add(1).expect("drain range start overflowed"),
+                Bound::Unbounded => 0,
+            };
+            let end = match range.end_bound() {
+                Bound::Included(&end) => end.checked_add(1).expect("drain range end overflowed"),
+                Bound::Excluded(&end) => end,
+                Bound::Unbounded => len,
+            };
+            assert!(start <= end, "drain range starts at {} but ends at {}", start, end);
+            assert!(end <= len, "drain range end (is {}) should be <= len (is {})", end, len);
+
+            local.drain_range_into(start..end, &mut drained);
+        });
+        drained.into_iter()
+    }
+
+    /// Move all of the values pushed directly into `other` onto the end
+    /// of this scope's own values, as with [Vec::append], leaving `other`
+    /// empty. Children of either scope are left untouched. Both scopes'
+    /// write locks are taken once, in address order so two calls with
+    /// the sides swapped can't deadlock. Does nothing if `other` is this
+    /// scope.
+    pub fn append(&self, other: &Self) {
+        if self.ptr_eq(other) {
+            return;
+        }
+
+        let (mine, mut theirs) = if Arc::as_ptr(&self.node) < Arc::as_ptr(&other.node) {
+            let mine = lock::write(&self.node.inner);
+            (mine, lock::write(&other.node.inner))
+        } else {
+            let theirs = lock::write(&other.node.inner);
+            (lock::write(&self.node.inner), theirs)
+        };
+        Self::update_local(mine, |local| local.append(&mut theirs));
+        drop(theirs);
+
+        self.len_changed();
+        other.len_changed();
+    }
+
+    /// Split off the values pushed directly into this scope from `at`
+    /// onwards and return them, as with [Vec::split_off], leaving this
+    /// scope with the first `at`. Children are left untouched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `at` is greater than the number of values held directly
+    /// by this scope, in which case nothing is removed and the scope
+    /// remains usable.
+    pub fn split_off(&self, at: usize) -> Vec<T> {
+        let mut tail = Vec::new();
+        self.write_local(|local| {
+            let len = local.values.len();
+            assert!(at <= len, "`at` split index (is {}) should be <= len (is {})", at, len);
+            local.drain_range_into(at..len, &mut tail);
+        });
+        tail
+    }
+
+    /// Remove all of the values pushed directly into this scope, leaving
+    /// its children untouched.
+    pub fn clear(&self) {
+        self.truncate(0);
+    }
+
+    /// Remove the values of this scope and every one of its descendants,
+    /// taking each scope's write lock in turn. The shape of the tree is
+    /// kept, so handles to descendants stay attached and can carry on
+    /// being pushed to.
+    pub fn clear_all(&self) {
+        self.for_each_scope(|_| true, ScopedVec::clear);
+    }
+
+    /// Clear the values of every descendant of this scope while keeping
+    /// this scope's own values. If `remove` is true the now empty child
+    /// scopes are removed from this scope as well, otherwise the shape
+    /// of the tree is kept as is.
+    ///
+    /// Handles held to removed scopes become detached roots without any
+    /// values, see [ScopedVec::path_from_root].
+    pub fn clear_descendants(&self, remove: bool) {
+        let children = if remove {
+            std::mem::take(&mut *lock::write(&self.node.children))
+        } else {
+            self.child_handles()
+        };
+
+        for child in &children {
+            child.clear_all();
+
+            if remove {
+                child.detach();
+            }
+        }
+
+        if remove {
+            self.len_changed();
+        }
+    }
+
+    /// Shorten this scope to the first `len` values pushed directly into
+    /// it, dropping the rest, as with [Vec::truncate]. Children are left
+    /// untouched, so this rolls a scope back to a length captured earlier
+    /// using [ScopedVec::len]. Does nothing if the scope is already
+    /// shorter than `len`.
+    pub fn truncate(&self, len: usize) {
+        self.write_local(|local| local.truncate(len));
+    }
+
+    /// Remove values from the end of the flattened view, as given by
+    /// [ScopedVec::iter], until this scope and its descendants hold at
+    /// most `total` values between them. Values are evicted from the
+    /// last visited scopes first, so the earliest values are kept. The
+    /// shape of the tree is left as is, even where scopes end up empty.
+    pub fn truncate_to_len(&self, total: usize) {
+        self.truncate_subtree(total);
+    }
+
+    /// Truncate this subtree to at most `budget` values, returning the
+    /// number of values left.
+    fn truncate_subtree(&self, budget: usize) -> usize {
+        let mut remaining = budget;
+
+        self.write_local(|local| {
+            let len = local.values.len().min(remaining);
+            local.truncate(len);
+            remaining -= len;
+        });
+
+        for child in self.child_handles() {
+            remaining -= child.truncate_subtree(remaining);
+        }
+
+        budget - remaining
+    }
+
+    /// Move the values of every scope exactly `depth` levels below this
+    /// one up from their descendants into the scope itself, then remove
+    /// the now empty descendants. Values end up in the order they were
+    /// visited by [ScopedVec::iter], so iterating over this scope gives
+    /// the same values before and after. A `depth` of 0 collapses the
+    /// whole tree into this scope.
+    ///
+    /// Any handles still held to the removed descendants become detached,
+    /// empty roots - values pushed to them afterwards aren't visible from
+    /// this tree.
+    pub fn collapse_below(&self, depth: usize) {
+        if depth > 0 {
+            for child in self.child_handles() {
+                child.collapse_below(depth - 1);
+            }
+            return;
+        }
+
+        let children = std::mem::take(&mut *lock::write(&self.node.children));
+
+        let mut values = Vec::new();
+        for child in &children {
+            child.take_subtree_into(&mut values);
+        }
+
+        self.write_local(|local| {
+            for val in values {
+                local.push(val);
+            }
+        });
+    }
+
+    /// Merge the values of up to `levels` levels of this scope's
+    /// descendants up into this scope, removing the scopes they came
+    /// from. Scopes deeper than `levels` are kept intact and become
+    /// children of this scope, so the depth of the tree is reduced by
+    /// `levels`.
+    ///
+    /// The order given by [ScopedVec::iter] is preserved. As a scope's
+    /// values always come before its children, values which followed one
+    /// of the kept subtrees are moved into a new child scope placed after
+    /// it rather than into this scope.
+    ///
+    /// Handles held to the removed scopes become detached, empty roots.
+    pub fn collapse_levels(&self, levels: usize) {
+        if levels == 0 {
+            return;
+        }
+
+        let children = std::mem::take(&mut *lock::write(&self.node.children));
+        let mut items = Vec::new();
+        for child in children {
+            child.collapse_into(levels, &mut items);
+        }
+
+        let mut leading = Vec::new();
+        let mut new_children: Vec<ScopedVec<T, S>> = Vec::new();
+        let mut carrier: Option<ScopedVec<T, S>> = None;
+
+        for item in items {
+            match item {
+                Collapsed::Value(val) if new_children.is_empty() => leading.push(val),
+                Collapsed::Value(val) => carrier.get_or_insert_with(|| {
+                    let new = self.new_child();
+                    new_children.push(new.handle());
+                    new
+                }).push(val),
+                Collapsed::Subtree(subtree) => {
+                    carrier = None;
+                    *lock::write(&subtree.node.parent) = Arc::downgrade(&self.node);
+                    new_children.push(subtree);
+                },
+            }
+        }
+
+        self.write_local(|local| {
+            for val in leading {
+                local.push(val);
+            }
+        });
+
+        let mut children = lock::write(&self.node.children);
+        new_children.append(&mut children);
+        *children = new_children;
+    }
+
+    /// Remove this scope from the tree, moving its values onto the end
+    /// of `out` followed by those of its descendants up to `levels - 1`
+    /// levels down, and the subtrees below that.
+    fn collapse_into(self, levels: usize, out: &mut Vec<Collapsed<T, S>>) {
+        if levels == 0 {
+            out.push(Collapsed::Subtree(self));
+            return;
+        }
+
+        let mut values = Vec::new();
+        self.write_local(|local| local.take_all_into(&mut values));
+        out.extend(values.into_iter().map(Collapsed::Value));
+
+        self.detach();
+        let children = std::mem::take(&mut *lock::write(&self.node.children));
+        for child in children {
+            child.collapse_into(levels - 1, out);
+        }
+    }
+
+    /// Move every value in this subtree onto the end of `out` in
+    /// iteration order, leaving each scope empty, detached and without
+    /// children.
+    fn take_subtree_into(&self, out: &mut Vec<T>) {
+        self.detach();
+        self.write_local(|local| local.take_all_into(out));
+
+        let children = std::mem::take(&mut *lock::write(&self.node.children));
+        for child in &children {
+            child.take_subtree_into(out);
+        }
+    }
+
+    /// Returns the number of values pushed directly into this scope,
+    /// ignoring its children.
+    pub fn len(&self) -> usize {
+        lock::read(&self.node.inner).values.len()
+    }
+
+    /// Returns the number of values in this scope and all of its
+    /// descendants, the same as counting [ScopedVec::iter] but only
+    /// reading each scope's length.
+    pub fn total_len(&self) -> usize {
+        let mut total = 0;
+        self.for_each_scope(|_| true, |scope| total += scope.len());
+        total
+    }
+
+    /// Check if no values have been pushed directly into this scope,
+    /// ignoring its children, in keeping with [ScopedVec::len].
+    pub fn is_empty(&self) -> bool {
+        lock::read(&self.node.inner).values.is_empty()
+    }
+
+    /// Check if there are no values in this scope or any of its
+    /// children. A scope with children that hold no values is still
+    /// considered empty, see [ScopedVec::has_no_children] for the
+    /// structural check.
+    ///
+    /// This returns as soon as the first value is found rather than
+    /// walking the entire tree.
+    pub fn is_empty_deep(&self) -> bool {
+        self.is_empty()
+            && lock::read(&self.node.children).iter().all(ScopedVec::is_empty_deep)
+    }
+
+    /// Check if this scope has no child scopes, regardless of whether
+    /// it holds any values itself.
+    pub fn has_no_children(&self) -> bool {
+        lock::read(&self.node.children).is_empty()
+    }
+
+    /// Validate the internal consistency of this tree, panicking if
+    /// anything is amiss. Currently checks that no scope is reachable
+    /// from itself, that no scope is attached at more than one place in
+    /// the tree and that value indexes match the values they're
+    /// indexing.
+    ///
+    /// This is a no-op in builds without `debug_assertions`.
+    pub fn debug_assert_invariants(&self) {
+        #[cfg(debug_assertions)]
+        {
+            let mut seen = std::collections::HashSet::new();
+            let mut path = Vec::new();
+            self.assert_invariants_inner(&mut seen, &mut path);
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn assert_invariants_inner(&self, seen: &mut std::collections::HashSet<*const ()>, path: &mut Vec<*const ()>) {
+        let id = Arc::as_ptr(&self.node) as *const ();
+
+        assert!(!path.contains(&id), "scope is its own descendant");
+        assert!(seen.insert(id), "scope is attached at more than one place in the tree");
+        lock::read(&self.node.inner).assert_consistent();
+
+        path.push(id);
+        for child in self.child_handles() {
+            child.assert_invariants_inner(seen, path);
+        }
+        path.pop();
+    }
+
+    /// Merge this tree with `other` by walking both in parallel,
+    /// calling `f` with the elements at each corresponding position to
+    /// build a new, detached tree.
+    ///
+    /// Local elements are paired up by index, and `f` is given `None`
+    /// for the side that's shorter. Child scopes are paired up by their
+    /// index within the parent, so where one side has more children
+    /// than the other the extra children are merged against an absent
+    /// scope - `f` sees `None` on the missing side for each of their
+    /// elements. The resulting tree therefore has the union of both
+    /// shapes. Elements for which `f` returns `None` are left out.
+    pub fn merge_with<F>(&self, other: &ScopedVec<T, S>, mut f: F) -> ScopedVec<T, S>
+        where F: FnMut(Option<&T>, Option<&T>) -> Option<T>
+    {
+        let mut merged = Self::with_storage();
+        Self::merge_into(&mut merged, Some(self), Some(other), &mut f);
+        merged
+    }
+
+    fn merge_into<F>(out: &mut ScopedVec<T, S>, left: Option<&ScopedVec<T, S>>, right: Option<&ScopedVec<T, S>>, f: &mut F)
+        where F: FnMut(Option<&T>, Option<&T>) -> Option<T>
+    {
+        {
+            let left_inner = left.map(|v| lock::read(&v.node.inner));
+            let right_inner = right.map(|v| lock::read(&v.node.inner));
+
+            let left_len = left_inner.as_ref().map_or(0, |v| v.values.len());
+            let right_len = right_inner.as_ref().map_or(0, |v| v.values.len());
+
+            for i in 0..left_len.max(right_len) {
+                let l = left_inner.as_ref().and_then(|v| v.values.get(i));
+                let r = right_inner.as_ref().and_then(|v| v.values.get(i));
+
+                if let Some(val) = f(l, r) {
+                    out.push(val);
+                }
+            }
+        }
+
+        let left_children = left.map(ScopedVec::child_handles).unwrap_or_default();
+        let right_children = right.map(ScopedVec::child_handles).unwrap_or_default();
+
+        for i in 0..left_children.len().max(right_children.len()) {
+            let mut child = out.scope();
+            Self::merge_into(&mut child, left_children.get(i), right_children.get(i), f);
+        }
+    }
+
+    /// Runs `f` against this scope's values while holding the write lock.
+    /// A panic from within `f`, which may be running user code, is caught
+    /// so the scope's bookkeeping can be repaired and the lock released
+    /// without poisoning it before the panic is resumed.
+    fn write_local<R, F: FnOnce(&mut Local<T, S>) -> R>(&self, f: F) -> R {
+        let result = Self::update_local(lock::write(&self.node.inner), f);
+        self.len_changed();
+        result
+    }
+
+    /// Called after anything which may have changed the number of values
+    /// in this scope or its descendants, once the locks have been
+    /// released.
+    fn len_changed(&self) {
+        #[cfg(feature = "tokio")]
+        self.notify_len_changed();
+    }
+
+    /// Run `f` under a write guard that's already been taken, in the
+    /// same way as [ScopedVec::write_local].
+    fn update_local<R, F: FnOnce(&mut Local<T, S>) -> R>(mut local: RwLockWriteGuard<'_, Local<T, S>>, f: F) -> R {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&mut local)));
+        if result.is_err() {
+            local.repair();
+        }
+        drop(local);
+
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// Call `f` with this scope and each of its descendants in depth first
+    /// order, along with the path of child indices to each from this one.
+    /// Each scope's children are looked up after `f` has been called on
+    /// it, and no locks are held while `f` runs.
+    fn walk_with_paths<F: FnMut(&[usize], &ScopedVec<T, S>)>(&self, mut f: F) {
+        let mut stack = vec![(self.clone(), Vec::new())];
+
+        while let Some((scope, path)) = stack.pop() {
+            f(&path, &scope);
+
+            for (i, child) in scope.child_handles().into_iter().enumerate().rev() {
+                let mut child_path = path.clone();
+                child_path.push(i);
+                stack.push((child, child_path));
+            }
+        }
+    }
+
+    /// Clones the handles of this scope's direct children so they can
+    /// be walked without holding on to the `children` lock.
+    fn child_handles(&self) -> Vec<ScopedVec<T, S>> {
+        lock::read(&self.node.children).clone()
+    }
+}
+
+impl<T, S: Storage<T>> Default for ScopedVec<T, S> {
+    fn default() -> Self {
+        Self::with_storage()
+    }
+}
+
+impl<T: Clone, S: Storage<T>> ScopedVec<T, S> {
+    /// Clone this tree into an owned, recursive representation which
+    /// preserves the shape of the tree, unlike [ScopedVec::iter].
+    pub fn to_nested_vec(&self) -> NestedVec<T> {
+        NestedVec {
+            values: lock::read(&self.node.inner).values.as_slice().to_vec(),
+            children: self.child_handles().iter().map(ScopedVec::to_nested_vec).collect()
+        }
+    }
+
+    /// Binary search the values of this tree using `f` to compare each
+    /// value against the target, as with [slice::binary_search_by].
+    ///
+    /// The whole tree must be sorted in the order given by
+    /// [ScopedVec::iter], not just each scope's own values: a scope's
+    /// values must be no greater than those of its first child, and the
+    /// last values of each child no greater than the first of the next.
+    /// A scope holding `[1, 5]` with a child holding `[2, 3]` isn't
+    /// sorted, and the results for such a tree are meaningless. See
+    /// [ScopedVec::sorted_iter] for searching a tree which isn't.
+    ///
+    /// If a match is found, returns the path to the scope holding it
+    /// relative to this one along with its position among that scope's
+    /// own values. If there are several matches, any one of them may be
+    /// returned. Otherwise returns the path and position at which the
+    /// target could be inserted, with [ScopedVec::insert] on the scope
+    /// at that path, to keep the tree sorted. That's just after the
+    /// greatest value less than the target, or the start of this scope
+    /// if there's no such value.
+    ///
+    /// The read locks of every scope are held for the duration of the
+    /// search, so the result is consistent with a single snapshot of the
+    /// tree. They're taken in address order, as everywhere else more than
+    /// one scope is locked at once, so this can't deadlock with
+    /// [ScopedVec::append] or comparisons between scopes.
+    pub fn binary_search_by<F: FnMut(&T) -> Ordering>(&self, mut f: F) -> Result<(Vec<usize>, usize), (Vec<usize>, usize)> {
+        let mut scopes = Vec::new();
+        self.walk_with_paths(|path, scope| scopes.push((scope.clone(), path.to_vec())));
+
+        let mut order: Vec<_> = (0..scopes.len()).collect();
+        order.sort_by_key(|&i| Arc::as_ptr(&scopes[i].0.node));
+        let mut locked: Vec<_> = order.into_iter().map(|i| (i, lock::read(&scopes[i].0.node.inner))).collect();
+        locked.sort_by_key(|(i, _)| *i);
+        let locals: Vec<_> = locked.into_iter().map(|(_, local)| local).collect();
+        let mut starts = Vec::with_capacity(locals.len());
+        let mut total = 0;
+        for local in &locals {
+            starts.push(total);
+            total += local.values.len();
+        }
+
+        // the last scope starting at or before `i` is the one holding it,
+        // as any empty scopes sharing its start come before it
+        let locate = |i: usize| {
+            let scope = starts.partition_point(|&start| start <= i) - 1;
+            (scope, i - starts[scope])
+        };
+
+        let (mut lo, mut hi) = (0, total);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (scope, index) = locate(mid);
+            match f(&locals[scope].values.as_slice()[index]) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Ok((scopes[scope].1.clone(), index)),
+            }
+        }
+
+        match lo.checked_sub(1) {
+            Some(before) => {
+                let (scope, index) = locate(before);
+                Err((scopes[scope].1.clone(), index + 1))
+            }
+            None => Err((Vec::new(), 0)),
+        }
+    }
+
+    /// Clone the values of this tree into a `Vec`, in the order given by
+    /// [ScopedVec::iter]. Unlike collecting from the iterator, only one
+    /// scope is read locked at a time, and each only while its values
+    /// are being cloned. The result therefore isn't an atomic snapshot
+    /// of the whole tree if it's being modified concurrently.
+    pub fn to_vec(&self) -> Vec<T> {
+        let mut out = Vec::new();
+        self.for_each_scope(|_| true, |scope| {
+            out.extend_from_slice(lock::read(&scope.node.inner).values.as_slice());
+        });
+        out
+    }
+
+    /// Flatten this tree into a `Vec` in depth first order, like
+    /// [ScopedVec::iter], with a clone of `marker` between the values of
+    /// each scope and the next, so the values held by each scope can be
+    /// picked back out by splitting on `marker`.
+    ///
+    /// Every scope is separated, including those without any values of
+    /// their own, so empty scopes show up as adjacent markers and a tree
+    /// of `n` scopes always has `n - 1` markers. The boundaries can only
+    /// be recovered if `marker` doesn't appear among the values.
+    pub fn to_vec_with_markers(&self, marker: T) -> Vec<T> {
+        let mut out = Vec::new();
+        let mut first = true;
+
+        self.for_each_scope(|_| true, |scope| {
+            if !std::mem::take(&mut first) {
+                out.push(marker.clone());
+            }
+            out.extend_from_slice(lock::read(&scope.node.inner).values.as_slice());
+        });
+
+        out
+    }
+
+    /// Snapshot this tree split into two parts: a copy of the scopes no
+    /// more than `depth` levels below this one, and detached copies of
+    /// each subtree hanging below that, in depth first order. With a
+    /// `depth` of 0 the first part only holds this scope's own values
+    /// and each child becomes one of the detached subtrees.
+    pub fn split_at_depth(&self, depth: usize) -> (ScopedVec<T, S>, Vec<ScopedVec<T, S>>) {
+        fn cut<T>(nested: &mut NestedVec<T>, depth: usize, below: &mut Vec<NestedVec<T>>) {
+            if depth == 0 {
+                below.append(&mut nested.children);
+            } else {
+                for child in &mut nested.children {
+                    cut(child, depth - 1, below);
+                }
+            }
+        }
+
+        let mut top = self.to_nested_vec();
+        let mut below = Vec::new();
+        cut(&mut top, depth, &mut below);
+
+        let snapshot = |nested| {
+            let scope = Self::with_storage();
+            scope.extend_from_nested(nested);
+            scope
+        };
+        (snapshot(top), below.into_iter().map(snapshot).collect())
+    }
+
+    /// Group the values of this tree into runs of consecutive values, in
+    /// the order given by [ScopedVec::iter], which sit at the same depth
+    /// below this scope. A depth can have several runs where its values
+    /// are interrupted by those of a deeper or shallower scope, unlike
+    /// [ScopedVec::collect_by_depth].
+    pub fn iter_by_depth_runs(&self) -> std::vec::IntoIter<(usize, Vec<T>)> {
+        let mut runs: Vec<(usize, Vec<T>)> = Vec::new();
+        let mut depth = 0;
+
+        for event in self.iter_events() {
+            match event {
+                IterEvent::EnterScope { depth: d, .. } => depth = d,
+                IterEvent::LeaveScope => depth = depth.saturating_sub(1),
+                IterEvent::Value(val) => match runs.last_mut() {
+                    Some((run_depth, run)) if *run_depth == depth => run.push(val.clone()),
+                    _ => runs.push((depth, vec![val.clone()])),
+                },
+            }
+        }
+
+        runs.into_iter()
+    }
+
+    /// Group the values of this tree by their depth below this scope,
+    /// with the values at depth `n` at index `n` of the returned `Vec`
+    /// in the order given by [ScopedVec::iter].
+    pub fn collect_by_depth(&self) -> Vec<Vec<T>> {
+        let mut by_depth: Vec<Vec<T>> = Vec::new();
+        let mut depth = 0;
+
+        for event in self.iter_events() {
+            match event {
+                IterEvent::EnterScope { depth: d, .. } => depth = d,
+                IterEvent::LeaveScope => depth = depth.saturating_sub(1),
+                IterEvent::Value(val) => {
+                    if by_depth.len() <= depth {
+                        by_depth.resize_with(depth + 1, Vec::new);
+                    }
+                    by_depth[depth].push(val.clone());
+                },
+            }
+        }
+
+        by_depth
+    }
+
+    /// Call `f` with a clone of every value in this scope and its
+    /// descendants, in the order given by [ScopedVec::iter], without
+    /// holding any locks while `f` runs. Values are cloned out of each
+    /// scope `batch` at a time under its read lock, which is released
+    /// before `f` is called on them, so writers are only ever blocked
+    /// for as long as it takes to clone a batch.
+    ///
+    /// As locks are released between batches, the tree can change while
+    /// it's being walked. Values pushed to a scope before the walk has
+    /// moved past it are visited, while a scope's children are only
+    /// looked up once all of its values have been visited. Values
+    /// removed from a scope part way through may cause others to be
+    /// skipped or visited twice.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch` is 0.
+    pub fn for_each_relaxed<F: FnMut(T)>(&self, batch: usize, mut f: F) {
+        assert!(batch != 0, "for_each_relaxed batch must be non-zero");
+
+        let mut stack = vec![self.clone()];
+
+        while let Some(scope) = stack.pop() {
+            let mut pos = 0;
+
+            loop {
+                let values: Vec<T> = {
+                    let local = lock::read(&scope.node.inner);
+                    let values = local.values.as_slice();
+                    values[pos.min(values.len())..(pos + batch).min(values.len())].to_vec()
+                };
+
+                if values.is_empty() {
+                    break;
+                }
+
+                pos += values.len();
+                values.into_iter().for_each(&mut f);
+            }
+
+            stack.extend(scope.child_handles().into_iter().rev());
+        }
+    }
+
+    /// Merge the values of every scope into a single sorted `Vec`,
+    /// assuming the values held directly by each scope are already
+    /// sorted. This is a k-way merge across the scopes, which is cheaper
+    /// than flattening the tree and sorting the result.
+    ///
+    /// Equal values are returned in the order they'd be visited by
+    /// [ScopedVec::iter]. If a scope isn't sorted, the result still holds
+    /// every value in the tree, but the out of order values are emitted
+    /// as they're reached so the output won't be sorted either.
+    pub fn merge_sorted(&self) -> Vec<T>
+        where T: Ord
+    {
+        let mut runs = Vec::new();
+        self.collect_local_runs(&mut runs);
+
+        let mut runs: Vec<_> = runs.into_iter().map(Vec::into_iter).collect();
+        let mut heads: BinaryHeap<_> = runs.iter_mut()
+            .enumerate()
+            .filter_map(|(run, values)| values.next().map(|v| Reverse((v, run))))
+            .collect();
+
+        let mut out = Vec::with_capacity(runs.iter().map(ExactSizeIterator::len).sum::<usize>() + heads.len());
+        while let Some(Reverse((val, run))) = heads.pop() {
+            out.push(val);
+
+            if let Some(next) = runs[run].next() {
+                heads.push(Reverse((next, run)));
+            }
+        }
+
+        out
+    }
+
+    /// Iterate over a snapshot of the values in this scope and its
+    /// descendants in ascending order, without reordering any scope.
+    /// Equal values keep the order given by [ScopedVec::iter]. The
+    /// snapshot is taken with [ScopedVec::to_vec] before this returns,
+    /// so no locks are held while iterating. See
+    /// [ScopedVec::merge_sorted] if each scope is already sorted.
+    pub fn sorted_iter(&self) -> std::vec::IntoIter<T>
+        where T: Ord
+    {
+        let mut values = self.to_vec();
+        values.sort();
+        values.into_iter()
+    }
+
+    /// Binary search the values of this tree for `val`, as with
+    /// [slice::binary_search]. The whole tree must be sorted in the order
+    /// given by [ScopedVec::iter], see [ScopedVec::binary_search_by].
+    pub fn binary_search(&self, val: &T) -> Result<(Vec<usize>, usize), (Vec<usize>, usize)>
+        where T: Ord
+    {
+        self.binary_search_by(|v| v.cmp(val))
+    }
+
+    /// Returns the `k` largest values in this scope and its descendants,
+    /// largest first. Only `k` values are held at a time, and each
+    /// scope's read lock is only held while its own values are looked
+    /// at. If there are fewer than `k` values, all of them are returned.
+    pub fn top_k(&self, k: usize) -> Vec<T>
+        where T: Ord
+    {
+        self.select_k(k, |val, worst: Option<&Reverse<T>>| match worst {
+            Some(Reverse(worst)) if val <= worst => None,
+            _ => Some(Reverse(val.clone())),
+        }).into_sorted_vec().into_iter().map(|Reverse(v)| v).collect()
+    }
+
+    /// Returns the `k` values in this scope and its descendants with the
+    /// largest keys, as given by `f`, largest first. `f` is called once
+    /// for every value. Which of several values with equal keys are
+    /// returned is unspecified.
+    pub fn top_k_by_key<K: Ord, F: FnMut(&T) -> K>(&self, k: usize, mut f: F) -> Vec<T> {
+        self.select_k(k, |val, worst: Option<&Reverse<Keyed<K, T>>>| {
+            let key = f(val);
+            match worst {
+                Some(Reverse(worst)) if key <= worst.key => None,
+                _ => Some(Reverse(Keyed { key, value: val.clone() })),
+            }
+        }).into_sorted_vec().into_iter().map(|Reverse(keyed)| keyed.value).collect()
+    }
+
+    /// Returns the `k` smallest values in this scope and its descendants,
+    /// smallest first. This is the mirror of [ScopedVec::top_k].
+    pub fn bottom_k(&self, k: usize) -> Vec<T>
+        where T: Ord
+    {
+        self.select_k(k, |val, worst: Option<&T>| match worst {
+            Some(worst) if val >= worst => None,
+            _ => Some(val.clone()),
+        }).into_sorted_vec()
+    }
+
+    /// Offer every value in the tree to a max-heap holding at most `k`
+    /// entries, whose greatest entry is the worst of those kept. `offer`
+    /// is given the current worst entry once the heap is full, and
+    /// returns the entry to keep in its place, if any.
+    fn select_k<W, F>(&self, k: usize, mut offer: F) -> BinaryHeap<W>
+        where W: Ord, F: FnMut(&T, Option<&W>) -> Option<W>
+    {
+        let mut heap = BinaryHeap::new();
+        if k == 0 {
+            return heap;
+        }
+
+        self.for_each_scope(|_| true, |scope| {
+            let local = lock::read(&scope.node.inner);
+
+            for val in local.values.iter() {
+                if heap.len() < k {
+                    heap.extend(offer(val, None));
+                } else if let Some(entry) = offer(val, heap.peek()) {
+                    *heap.peek_mut().unwrap() = entry;
+                }
+            }
+        });
+
+        heap
+    }
+
+    /// Remove any child scope which is identical, in both its values and
+    /// the shape and values of its descendants, to an earlier sibling,
+    /// keeping the first of each. This is applied at every level of the
+    /// tree, deepest first, so siblings which only differed by their own
+    /// duplicated children are deduplicated too.
+    ///
+    /// Unlike comparing with `==`, which only looks at the flattened
+    /// values, scopes holding the same values split up differently are
+    /// not considered duplicates.
+    pub fn dedup_scopes(&self)
+        where T: PartialEq
+    {
+        let mut seen: Vec<NestedVec<T>> = Vec::new();
+        let mut duplicates = Vec::new();
+
+        for child in self.child_handles() {
+            child.dedup_scopes();
+
+            let nested = child.to_nested_vec();
+            if seen.contains(&nested) {
+                duplicates.push(Arc::as_ptr(&child.node));
+            } else {
+                seen.push(nested);
+            }
+        }
+
+        lock::write(&self.node.children).retain(|child| {
+            let keep = !duplicates.contains(&Arc::as_ptr(&child.node));
+            if !keep {
+                child.detach();
+            }
+            keep
+        });
+        self.len_changed();
+    }
+
+    fn collect_local_runs(&self, runs: &mut Vec<Vec<T>>) {
+        runs.push(lock::read(&self.node.inner).values.as_slice().to_vec());
+
+        for child in self.child_handles() {
+            child.collect_local_runs(runs);
+        }
+    }
+}
+
+impl<T: fmt::Debug, S: Storage<T>> fmt::Debug for ScopedVec<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let local = lock::read(&self.node.inner);
+        let mut debug = f.debug_struct("ScopedVec");
+        debug.field("values", &local.values.as_slice());
+        if let Some(provenance) = &local.provenance {
+            debug.field("provenance", provenance);
+        }
+        debug.field("children", &self.child_handles()).finish()
+    }
+}
+
+impl<T> From<NestedVec<T>> for ScopedVec<T> {
+    fn from(nested: NestedVec<T>) -> Self {
+        Self::from_nested(nested)
+    }
+}
+
+/// Consumes the tree, yielding its values in the order given by
+/// [ScopedVec::iter]. Values are moved out of any scope this is the
+/// last handle to, while scopes that are still shared with other handles
+/// are left untouched and have their values cloned instead.
+impl<T: Clone, S: Storage<T>> IntoIterator for ScopedVec<T, S> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let mut out = Vec::new();
+        self.into_values(&mut out);
+        out.into_iter()
+    }
+}
+
+impl<T: Clone, S: Storage<T>> ScopedVec<T, S> {
+    fn into_values(self, out: &mut Vec<T>) {
+        // consuming the scope isn't dropping it, so it's not promoted
+        let node = self.uncounted().node.clone();
+
+        match Arc::try_unwrap(node) {
+            Ok(node) => {
+                lock::into_inner(node.inner).take_all_into(out);
+
+                for child in lock::into_inner(node.children) {
+                    child.into_values(out);
+                }
+            },
+            Err(node) => {
+                out.extend(lock::read(&node.inner).values.iter().cloned());
+
+                let children = lock::read(&node.children).clone();
+                for child in children {
+                    child.into_values(out);
+                }
+            },
+        }
+    }
+}
+
+impl<T: PartialEq, S: Storage<T>> ScopedVec<T, S> {
+    pub fn contains(&self, val: &T) -> bool {
+        self.contains_local(val)
+            || self.child_handles().iter().any(|child| child.contains(val))
+    }
+
+    /// Returns the position of every occurrence of `val` in the values
+    /// returned by [ScopedVec::iter], in ascending order.
+    pub fn positions_of(&self, val: &T) -> Vec<usize> {
+        self.iter()
+            .enumerate()
+            .filter(|(_, v)| *v == val)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Remove every value in this scope and its descendants equal to one
+    /// before it in the order given by [ScopedVec::iter], keeping only
+    /// the first occurrence of each. See [ScopedVec::dedup_by].
+    pub fn dedup(&self) {
+        self.dedup_by(|a, b| a == b);
+    }
+
+    /// Replace the first value equal to `old`, in the order given by
+    /// [ScopedVec::iter], with `new`, returning whether there was one.
+    /// Scopes are searched one at a time, and only the scope holding the
+    /// match is write locked, while it's replaced. See
+    /// [ScopedVec::replace_every] to replace every match.
+    pub fn replace(&self, old: &T, new: T) -> bool {
+        let mut new = Some(new);
+        let mut stack = vec![self.clone()];
+
+        while let Some(scope) = stack.pop() {
+            // the match may be removed before the write lock is taken, in
+            // which case carry on looking
+            if scope.contains_local(old) {
+                Self::update_local(lock::write(&scope.node.inner), |local| {
+                    if let Some(i) = local.values.iter().position(|v| v == old) {
+                        let new = new.take().unwrap();
+                        local.update(i, |v| *v = new);
+                    }
+                });
+                if new.is_none() {
+                    return true;
+                }
+            }
+
+            stack.extend(scope.child_handles().into_iter().rev());
+        }
+
+        false
+    }
+
+    /// Remove and return the first value equal to `val`, in the order
+    /// given by [ScopedVec::iter], from this scope or any of its
+    /// descendants. Scopes are searched one at a time, and only the scope
+    /// holding the match is write locked, while it's removed.
+    pub fn remove_value(&self, val: &T) -> Option<T> {
+        let mut stack = vec![self.clone()];
+
+        while let Some(scope) = stack.pop() {
+            // the match may be removed before the write lock is taken, in
+            // which case carry on looking
+            if scope.contains_local(val) {
+                let removed = scope.write_local(|local| {
+                    let i = local.values.iter().position(|v| v == val)?;
+                    Some(local.remove(i))
+                });
+                if removed.is_some() {
+                    return removed;
+                }
+            }
+
+            stack.extend(scope.child_handles().into_iter().rev());
+        }
+
+        None
+    }
+
+    /// Replace every value equal to `old` in this scope and its
+    /// descendants with a clone of `new`, returning how many were
+    /// replaced. Each scope holding a match is write locked in turn
+    /// while its matches are replaced.
+    pub fn replace_every(&self, old: &T, new: T) -> usize
+        where T: Clone
+    {
+        let mut replaced = 0;
+        self.for_each_scope(|scope| scope.contains_local(old), |scope| {
+            Self::update_local(lock::write(&scope.node.inner), |local| {
+                for i in 0..local.values.len() {
+                    if local.values.as_slice()[i] == *old {
+                        local.update(i, |v| *v = new.clone());
+                        replaced += 1;
+                    }
+                }
+            });
+        });
+        replaced
+    }
+
+    /// Check if `val` was pushed directly into this scope, ignoring any
+    /// children.
+    pub fn contains_local(&self, val: &T) -> bool {
+        lock::read(&self.node.inner).contains(val)
+    }
+
+    /// Returns true if this tree and `other` have the same shape and
+    /// every pair of corresponding scopes holds the same values. Unlike
+    /// comparing with `==`, which only looks at the flattened values,
+    /// trees holding the same values split up differently aren't equal.
+    pub fn deep_eq(&self, other: &Self) -> bool {
+        if !self.with_values_pair(other, |left, right| left == right) {
+            return false;
+        }
+
+        let (left, right) = (self.child_handles(), other.child_handles());
+        left.len() == right.len() && left.iter().zip(&right).all(|(l, r)| l.deep_eq(r))
+    }
+}
+
+/// Compares the flattened contents of the tree, as yielded by
+/// [ScopedVec::iter], against a `Vec`, stopping at the first mismatch.
+impl<T: PartialEq, S: Storage<T>> PartialEq<Vec<T>> for ScopedVec<T, S> {
+    fn eq(&self, other: &Vec<T>) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<'a, T: PartialEq, S: Storage<T>> PartialEq<&'a [T]> for ScopedVec<T, S> {
+    fn eq(&self, other: &&'a [T]) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<T: PartialEq, S: Storage<T>, const N: usize> PartialEq<[T; N]> for ScopedVec<T, S> {
+    fn eq(&self, other: &[T; N]) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<T: PartialEq, S: Storage<T>> PartialEq<ScopedVec<T, S>> for Vec<T> {
+    fn eq(&self, other: &ScopedVec<T, S>) -> bool {
+        other == self
+    }
+}
+
+impl<T: PartialEq, S: Storage<T>> PartialEq<ScopedVec<T, S>> for &[T] {
+    fn eq(&self, other: &ScopedVec<T, S>) -> bool {
+        other == self
+    }
+}
+
+impl<T: PartialEq, S: Storage<T>, const N: usize> PartialEq<ScopedVec<T, S>> for [T; N] {
+    fn eq(&self, other: &ScopedVec<T, S>) -> bool {
+        other == self
+    }
+}
+
+/// Compares the flattened contents of two trees, so trees holding the
+/// same values split up differently are equal. The trees are locked in
+/// the same way as when they're ordered.
+impl<T: PartialEq, S: Storage<T>> PartialEq for ScopedVec<T, S> {
+    fn eq(&self, other: &Self) -> bool {
+        matches!(self.lockstep(other, |a, b| if a == b { None } else { Some(()) }), Lockstep::Equal)
+    }
+}
+
+impl<T: Eq, S: Storage<T>> Eq for ScopedVec<T, S> {}
+
+/// Lexicographically compares the flattened contents of two trees, in
+/// the order given by [ScopedVec::iter], stopping at the first values
+/// which differ.
+///
+/// Both trees are walked together, holding the read locks of at most
+/// one scope from each side at a time and taking them in a consistent
+/// order, so comparing a tree against one which shares scopes with it,
+/// such as its own descendant, can't deadlock.
+impl<T: PartialOrd, S: Storage<T>> PartialOrd for ScopedVec<T, S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.lockstep(other, |a, b| match a.partial_cmp(b) {
+            Some(Ordering::Equal) => None,
+            ordering => Some(ordering),
+        }) {
+            Lockstep::Differ(ordering) => ordering,
+            Lockstep::LeftShorter => Some(Ordering::Less),
+            Lockstep::RightShorter => Some(Ordering::Greater),
+            Lockstep::Equal => Some(Ordering::Equal),
+        }
+    }
+}
+
+impl<T: Ord, S: Storage<T>> Ord for ScopedVec<T, S> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.ptr_eq(other) {
+            return Ordering::Equal;
+        }
+
+        match self.lockstep(other, |a, b| match a.cmp(b) {
+            Ordering::Equal => None,
+            ordering => Some(ordering),
+        }) {
+            Lockstep::Differ(ordering) => ordering,
+            Lockstep::LeftShorter => Ordering::Less,
+            Lockstep::RightShorter => Ordering::Greater,
+            Lockstep::Equal => Ordering::Equal,
+        }
+    }
+}
+
+impl<T, S: Storage<T>> ScopedVec<T, S> {
+    /// Call `f` with the values held directly by this scope and `other`.
+    /// The locks are taken in address order, and only once if both are
+    /// the same scope, so two calls with the sides swapped can't
+    /// deadlock.
+    fn with_values_pair<R, F: FnOnce(&[T], &[T]) -> R>(&self, other: &Self, f: F) -> R {
+        if self.ptr_eq(other) {
+            let local = lock::read(&self.node.inner);
+            return f(local.values.as_slice(), local.values.as_slice());
+        }
+
+        if Arc::as_ptr(&self.node) < Arc::as_ptr(&other.node) {
+            let left = lock::read(&self.node.inner);
+            let right = lock::read(&other.node.inner);
+            f(left.values.as_slice(), right.values.as_slice())
+        } else {
+            let right = lock::read(&other.node.inner);
+            let left = lock::read(&self.node.inner);
+            f(left.values.as_slice(), right.values.as_slice())
+        }
+    }
+
+    /// Walk the values of this tree and `other` side by side, in the
+    /// order given by [ScopedVec::iter], until `differ` returns a value
+    /// for a pair of them or either side runs out.
+    fn lockstep<R, F: FnMut(&T, &T) -> Option<R>>(&self, other: &Self, mut differ: F) -> Lockstep<R> {
+        let mut left = Cursor::new(self);
+        let mut right = Cursor::new(other);
+
+        loop {
+            let (left_scope, right_scope) = match (&left.current, &right.current) {
+                (Some(l), Some(r)) => (l, r),
+                (None, None) => return Lockstep::Equal,
+                (None, Some(_)) => {
+                    if !right.advance_if_exhausted() {
+                        return Lockstep::LeftShorter;
+                    }
+                    continue;
+                }
+                (Some(_), None) => {
+                    if !left.advance_if_exhausted() {
+                        return Lockstep::RightShorter;
+                    }
+                    continue;
+                }
+            };
+
+            let (left_pos, right_pos) = (left.pos, right.pos);
+            let step = left_scope.with_values_pair(right_scope, |left_values, right_values| {
+                let left_values = &left_values[left_pos.min(left_values.len())..];
+                let right_values = &right_values[right_pos.min(right_values.len())..];
+
+                for (a, b) in left_values.iter().zip(right_values) {
+                    if let Some(r) = differ(a, b) {
+                        return Err(r);
+                    }
+                }
+
+                let compared = left_values.len().min(right_values.len());
+                Ok((compared, left_values.len() == compared, right_values.len() == compared))
+            });
+            let (compared, left_done, right_done) = match step {
+                Ok(step) => step,
+                Err(r) => return Lockstep::Differ(r),
+            };
+
+            left.pos += compared;
+            right.pos += compared;
+            if left_done {
+                left.next_scope();
+            }
+            if right_done {
+                right.next_scope();
+            }
+        }
+    }
+}
+
+/// Helpers for trees holding heterogeneous values, where each consumer
+/// only cares about the types it knows about.
+impl<S: Storage<Box<dyn Any + Send + Sync>>> ScopedVec<Box<dyn Any + Send + Sync>, S> {
+    /// Box `val` and push it onto this scope.
+    pub fn push_any<U: Any + Send + Sync>(&self, val: U) {
+        self.push(Box::new(val));
+    }
+
+    /// Iterate over the values in this scope and its children which are
+    /// of type `U`, skipping over any values of other types.
+    pub fn iter_of<U: Any>(&self) -> impl Iterator<Item = &U> {
+        self.iter().filter_map(|v| (**v).downcast_ref::<U>())
+    }
+
+    /// Check if this scope or any of its children contain a value of
+    /// type `U`.
+    pub fn contains_type<U: Any>(&self) -> bool {
+        self.iter().any(|v| (**v).is::<U>())
+    }
+}
+
+/// Helpers for trees holding weak references, which shouldn't keep the
+/// values they point to alive.
+impl<U, S: Storage<Weak<U>>> ScopedVec<Weak<U>, S> {
+    /// Push a weak reference to `arc` onto this scope.
+    pub fn push_downgraded(&self, arc: &Arc<U>) {
+        self.push(Arc::downgrade(arc));
+    }
+
+    /// Iterate over the values in this scope and its children which are
+    /// still alive, skipping over any which have been dropped.
+    pub fn iter_upgraded(&self) -> impl Iterator<Item = Arc<U>> + '_ {
+        self.iter().filter_map(Weak::upgrade)
+    }
+
+    /// Remove the references in this scope, ignoring its children, to
+    /// values which have been dropped. Returns the number of references
+    /// removed.
+    pub fn purge_dead(&self) -> usize {
+        self.write_local(|local| {
+            let before = local.values.len();
+            local.retain(|weak| weak.strong_count() > 0);
+            before - local.values.len()
+        })
+    }
+
+    /// Remove the references in this scope and all of its descendants to
+    /// values which have been dropped. Returns the number of references
+    /// removed.
+    pub fn purge_dead_deep(&self) -> usize {
+        let mut removed = 0;
+        self.for_each_scope(|_| true, |scope| removed += scope.purge_dead());
+        removed
+    }
+}
+
+/// A value held directly by a scope, returned by [ScopedVec::get]. The
+/// scope's read lock is held until this is dropped, so pushing to the
+/// scope on the same thread in the meantime will deadlock.
+pub struct ElementGuard<'a, T, S = Vec<T>> {
+    local: RwLockReadGuard<'a, Local<T, S>>,
+    index: usize,
+    _held: lock::Held,
+}
+
+impl<T, S: Storage<T>> std::ops::Deref for ElementGuard<'_, T, S> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.local.values.as_slice()[self.index]
+    }
+}
+
+impl<T: fmt::Debug, S: Storage<T>> fmt::Debug for ElementGuard<'_, T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+pub struct ScopedVecGuardHolder<'a, T, S = Vec<T>> {
+    inner: RwLockReadGuard<'a, Local<T, S>>,
+    children: RwLockReadGuard<'a, Vec<ScopedVec<T, S>>>,
+    _held: [lock::Held; 2],
+}
+
+impl<'a, T, S> ScopedVecGuardHolder<'a, T, S> {
+    /// Hold on to the read guards of `vec`'s locks, marking them as held
+    /// by this thread.
+    fn new(vec: &'a ScopedVec<T, S>, inner: RwLockReadGuard<'a, Local<T, S>>, children: RwLockReadGuard<'a, Vec<ScopedVec<T, S>>>) -> Self {
+        Self {
+            inner,
+            children,
+            _held: [lock::Held::new(&vec.node.inner), lock::Held::new(&vec.node.children)]
+        }
+    }
+
+    /// Read lock `vec`, waiting for any writers to finish.
+    fn read(vec: &'a ScopedVec<T, S>) -> Self {
+        Self::new(vec, lock::read(&vec.node.inner), lock::read(&vec.node.children))
+    }
+}
+
+type BoxedIter<'a, T> = Box<dyn Iterator<Item = &'a T> + 'a>;
+
+pub struct ScopedVecIterator<'a, T, S = Vec<T>> {
+    iterator: OwningHandle<Box<ScopedVecGuardHolder<'a, T, S>>, BoxedIter<'a, T>>,
+}
+
+/// An item moved up into a scope by [ScopedVec::collapse_levels].
+enum Collapsed<T, S> {
+    Value(T),
+    Subtree(ScopedVec<T, S>),
+}
+
+/// How far two trees walked by [ScopedVec::lockstep] got.
+enum Lockstep<R> {
+    Differ(R),
+    LeftShorter,
+    RightShorter,
+    Equal,
+}
+
+/// A position in a depth first walk over the values of a tree, which
+/// only holds on to handles so no locks are held between steps.
+struct Cursor<T, S> {
+    /// Scopes still to be visited, the next on top.
+    stack: Vec<ScopedVec<T, S>>,
+    current: Option<ScopedVec<T, S>>,
+    /// The position in `current`'s values of the next value.
+    pos: usize,
+}
+
+impl<T, S: Storage<T>> Cursor<T, S> {
+    fn new(root: &ScopedVec<T, S>) -> Self {
+        Self {
+            stack: Vec::new(),
+            current: Some(root.clone()),
+            pos: 0
+        }
+    }
+
+    /// Move on from the current scope to the next in depth first order.
+    fn next_scope(&mut self) {
+        if let Some(current) = self.current.take() {
+            self.stack.extend(current.child_handles().into_iter().rev());
+        }
+        self.current = self.stack.pop();
+        self.pos = 0;
+    }
+
+    /// Move on to the next scope if there are no values left in the
+    /// current one, returning whether it did.
+    fn advance_if_exhausted(&mut self) -> bool {
+        let exhausted = match &self.current {
+            Some(current) => lock::read(&current.node.inner).values.len() <= self.pos,
+            None => false,
+        };
+        if exhausted {
+            self.next_scope();
+        }
+        exhausted
+    }
+}
+
+/// A value kept by [ScopedVec::top_k_by_key], ordered by its key alone.
+struct Keyed<K, T> {
+    key: K,
+    value: T,
+}
+
+impl<K: Ord, T> PartialEq for Keyed<K, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<K: Ord, T> Eq for Keyed<K, T> {}
+
+impl<K: Ord, T> PartialOrd for Keyed<K, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord, T> Ord for Keyed<K, T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// Predicate deciding whether a [ScopedVecIterator] should descend into
+/// a child scope, shared between the iterators of every level of the
+/// tree.
+type DescendFilter<'a, T, S> = Rc<RefCell<dyn FnMut(&ScopedVec<T, S>) -> bool + 'a>>;
+
+impl<'a, T, S: Storage<T>> ScopedVecIterator<'a, T, S> {
+    fn new(vec: &'a ScopedVec<T, S>) -> Self {
+        Self::with_filter(vec, None)
+    }
+
+    fn with_filter(vec: &'a ScopedVec<T, S>, filter: Option<DescendFilter<'a, T, S>>) -> Self {
+        Self::from_guards(ScopedVecGuardHolder::read(vec), filter)
+    }
+
+    fn postorder(vec: &'a ScopedVec<T, S>) -> Self {
+        Self {
+            iterator: OwningHandle::new_with_fn(
+                Box::new(ScopedVecGuardHolder::read(vec)),
+                |g| {
+                    // the value behind the raw pointer `g` is boxed, so we're safe to dereference
+                    let guards = unsafe { &*g };
+
+                    Box::new(guards.children.iter()
+                        .flat_map(ScopedVec::iter_postorder)
+                        .chain(guards.inner.values.iter())) as Box<dyn Iterator<Item = &'a T>>
+                }
+            )
+        }
+    }
+
+    fn from_guards(guards: ScopedVecGuardHolder<'a, T, S>, filter: Option<DescendFilter<'a, T, S>>) -> Self {
+        Self {
+            iterator: OwningHandle::new_with_fn(
+                Box::new(guards),
+                |g| {
+                    // the value behind the raw pointer `g` is boxed, so we're safe to dereference
+                    let guards = unsafe { &*g };
+
+                    let children = match filter {
+                        None => Box::new(guards.children.iter()
+                            .flat_map(ScopedVec::iter)) as Box<dyn Iterator<Item = &'a T>>,
+                        Some(filter) => Box::new(guards.children.iter()
+                            .filter({
+                                let filter = filter.clone();
+                                move |child| (filter.borrow_mut())(child)
+                            })
+                            .flat_map(move |child| ScopedVecIterator::with_filter(child, Some(filter.clone())))),
+                    };
+
+                    Box::new(guards.inner.values.iter()
+                        .chain(children)) as Box<dyn Iterator<Item = &'a T>>
+                }
+            )
+        }
+    }
+}
+impl<'a, T, S: Storage<T>> Iterator for ScopedVecIterator<'a, T, S> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iterator.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assert_contents, assert_local, assert_structure, lock, nested, MismatchKind, NestedVec, ScopedVec, ScopedVecError, TryPushError};
+    use std::any::Any;
+    use std::panic::AssertUnwindSafe;
+    use std::sync::Arc;
+
+    #[test]
+    fn unscoped_standard() {
+        let root = ScopedVec::new();
+        root.push(3);
+        assert_eq!(root, [3]);
+    }
+
+    #[test]
+    fn scoped_cant_read_root() {
+        let root = ScopedVec::new();
+        root.push(3);
+
+        let scoped = root.scope();
+        assert_eq!(scoped, []);
+    }
+
+    #[test]
+    fn root_can_read_scoped() {
+        let root = ScopedVec::new();
+        root.push(3);
+
+        let scoped = root.scope();
+        scoped.push(4);
+
+        assert_eq!(root, vec![3, 4]);
+    }
+
+    #[test]
+    fn root_can_read_nested_scoped() {
+        let root = ScopedVec::new();
+        root.push(3);
+
+        let scoped = root.scope();
+        scoped.push(4);
+
+        let nested_scoped = scoped.scope();
+        nested_scoped.push(5);
+
+        assert_contents!(root, [3, 4, 5]);
+    }
+
+    #[test]
+    fn scoped_can_read_nested_scoped() {
+        let root = ScopedVec::new();
+        root.push(3);
+
+        let scoped = root.scope();
+        scoped.push(4);
+
+        let nested_scoped = scoped.scope();
+        nested_scoped.push(5);
+
+        assert_contents!(scoped, [4, 5]);
+    }
+
+    #[test]
+    fn nested_scoped_cant_read_backwards() {
+        let root = ScopedVec::new();
+        root.push(3);
+
+        let scoped = root.scope();
+        scoped.push(4);
+
+        let nested_scoped = scoped.scope();
+        nested_scoped.push(5);
+
+        assert_contents!(nested_scoped, [5]);
+    }
+
+    #[test]
+    fn can_drop_scopes() {
+        let root = ScopedVec::new();
+        root.push(3);
+
+        let scoped = root.scope();
+        scoped.push(4);
+
+        drop(root);
+
+        let nested_scoped = scoped.scope();
+        nested_scoped.push(5);
+
+        assert_contents!(scoped, [4, 5]);
+
+        drop(scoped);
+
+        assert_contents!(nested_scoped, [5]);
+    }
+
+    #[test]
+    fn diverged_scopes_can_be_read() {
+        let root = ScopedVec::new();
+        root.push(3);
+
+        let scoped = root.scope();
+        scoped.push(4);
+
+        let nested_scoped1 = scoped.scope();
+        nested_scoped1.push(5);
+
+        let nested_scoped2 = scoped.scope();
+        nested_scoped2.push(6);
+
+        assert_contents!(root, [3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn diverged_adjacent_scopes_cant_interact() {
+        let root = ScopedVec::new();
+        root.push(3);
+
+        let scoped1 = root.scope();
+        scoped1.push(4);
+
+        let scoped2 = root.scope();
+        scoped2.push(5);
+
+        assert_eq!(scoped1, [4]);
+
+        assert_eq!([5], scoped2);
+    }
+
+    #[test]
+    fn merge_with_equal_shapes() {
+        let left = ScopedVec::new();
+        left.push(1);
+        left.push(2);
+        left.scope().push(3);
+
+        let right = ScopedVec::new();
+        right.push(10);
+        right.push(20);
+        right.scope().push(30);
+
+        let merged = left.merge_with(&right, |l, r| Some(l.unwrap() + r.unwrap()));
+
+        assert_contents!(merged, [11, 22, 33]);
+    }
+
+    #[test]
+    fn merge_with_differing_shapes() {
+        let left = ScopedVec::new();
+        left.push(1);
+        left.push(2);
+        let left_scope = left.scope();
+        left_scope.push(3);
+        left_scope.scope().push(4);
+
+        let right = ScopedVec::new();
+        right.push(10);
+        right.scope().push(30);
+        right.scope().push(50);
+
+        let merged = left.merge_with(&right, |l, r| match (l, r) {
+            (Some(l), Some(r)) => Some(l + r),
+            (Some(v), None) | (None, Some(v)) => Some(*v),
+            (None, None) => unreachable!(),
+        });
+
+        assert_contents!(merged, [11, 2, 33, 4, 50]);
+        assert_structure!(merged, [11, 2; [33; [4]], [50]]);
+    }
+
+    #[test]
+    fn merge_with_can_drop_elements() {
+        let left = ScopedVec::new();
+        left.push(1);
+        left.push(2);
+
+        let right = ScopedVec::new();
+        right.push(1);
+        right.push(3);
+
+        let merged = left.merge_with(&right, |l, r| if l == r { l.cloned() } else { None });
+
+        assert_contents!(merged, [1]);
+    }
+
+    #[test]
+    fn any_values_can_be_extracted_by_type() {
+        let root: ScopedVec<Box<dyn Any + Send + Sync>> = ScopedVec::new();
+        root.push_any(3_u32);
+
+        let scoped = root.scope();
+        scoped.push_any("four");
+
+        let nested_scoped = scoped.scope();
+        nested_scoped.push_any(5_u32);
+        nested_scoped.push_any("six");
+
+        let mut iter = root.iter_of::<u32>();
+        assert_eq!(iter.next(), Some(&3));
+        assert_eq!(iter.next(), Some(&5));
+        assert_eq!(iter.next(), None);
+
+        let mut iter = root.iter_of::<&str>();
+        assert_eq!(iter.next(), Some(&"four"));
+        assert_eq!(iter.next(), Some(&"six"));
+        assert_eq!(iter.next(), None);
+
+        assert!(root.contains_type::<u32>());
+        assert!(scoped.contains_type::<&str>());
+        assert!(!scoped.contains_type::<String>());
+    }
+
+    #[test]
+    fn empty_scopes_are_value_empty_but_not_structurally_empty() {
+        let root: ScopedVec<i32> = ScopedVec::new();
+        assert!(root.is_empty());
+        assert!(root.has_no_children());
+
+        let scoped = root.scope();
+        scoped.scope();
+        assert!(root.is_empty());
+        assert!(root.is_empty_deep());
+        assert!(!root.has_no_children());
+        assert!(scoped.is_empty());
+        assert!(scoped.is_empty_deep());
+        assert!(!scoped.has_no_children());
+    }
+
+    #[test]
+    fn values_in_children_make_parents_non_empty() {
+        let root = ScopedVec::new();
+        let scoped = root.scope();
+        let nested_scoped = scoped.scope();
+        nested_scoped.push(3);
+
+        assert!(!root.is_empty_deep());
+        assert!(!scoped.is_empty_deep());
+        assert!(!nested_scoped.is_empty());
+        assert!(nested_scoped.has_no_children());
+    }
+
+    #[test]
+    fn is_empty_ignores_values_in_children() {
+        let root = ScopedVec::new();
+        let scoped = root.scope();
+        scoped.scope().push(3);
+
+        assert!(root.is_empty());
+        assert!(scoped.is_empty());
+        assert!(!root.is_empty_deep());
+
+        root.push(1);
+        assert!(!root.is_empty());
+        assert!(scoped.is_empty());
+    }
+
+    #[test]
+    fn split_for_parallel_creates_children() {
+        let root = ScopedVec::new();
+        root.push(1);
+
+        let scopes = root.split_for_parallel(2);
+        assert_eq!(scopes.len(), 2);
+        scopes[1].push(3);
+        scopes[0].push(2);
+
+        assert_contents!(root, [1, 2, 3]);
+    }
+
+    #[test]
+    fn fan_out_creates_a_child_per_seed() {
+        let root = ScopedVec::new();
+        root.push(0);
+
+        let workers = root.fan_out(1..=3);
+        assert_eq!(workers.len(), 3);
+        workers[1].push(4);
+
+        assert_structure!(root, [0; [1], [2, 4], [3]]);
+    }
+
+    #[test]
+    fn scatter_gather_across_threads() {
+        let root = ScopedVec::new();
+
+        let results = root.scatter_gather(8, |i, scope| {
+            for v in i * 100..(i + 1) * 100 {
+                scope.push(v);
+            }
+            i
+        });
+        assert_eq!(results, (0..8).collect::<Vec<_>>());
+
+        let mut values: Vec<_> = root.iter().copied().collect();
+        values.sort_unstable();
+        assert_eq!(values, (0..800).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drain_leaves_leaves_interior_values() {
+        let root = ScopedVec::new();
+        root.push(1);
+
+        let scoped1 = root.scope();
+        scoped1.push(2);
+        scoped1.scope().push(3);
+        scoped1.scope().push(4);
+
+        let scoped2 = root.scope();
+        scoped2.push(5);
+
+        assert_eq!(root.drain_leaves(), vec![3, 4, 5]);
+
+        assert_contents!(root, [1, 2]);
+
+        assert_local!(scoped1, [2]);
+        assert!(scoped2.is_empty());
+        assert!(scoped2.is_empty_deep());
+        assert!(root.drain_leaves().is_empty());
+    }
+
+    #[test]
+    fn drain_leaves_on_leaf_drains_itself() {
+        let root = ScopedVec::new();
+        root.push(1);
+        root.push(2);
+
+        assert_eq!(root.drain_leaves(), vec![1, 2]);
+        assert!(root.is_empty());
+        assert!(root.is_empty_deep());
+    }
+
+    #[test]
+    fn well_formed_tree_passes_invariants() {
+        let root = ScopedVec::new();
+        root.push(1);
+        let scoped = root.scope();
+        scoped.push(2);
+        scoped.scope().push(3);
+        root.scope();
+
+        root.debug_assert_invariants();
+        scoped.debug_assert_invariants();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "scope is its own descendant")]
+    fn cyclic_tree_fails_invariants() {
+        let root: ScopedVec<i32> = ScopedVec::new();
+        let scoped = root.scope();
+        lock::write(&scoped.node.children).push(root.clone());
+
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn indexed_contains() {
+        let root = ScopedVec::new_indexed();
+        root.push(1);
+
+        let scoped = root.scope();
+        scoped.push(2);
+        scoped.push(2);
+
+        assert!(root.contains(&1));
+        assert!(root.contains(&2));
+        assert!(!root.contains(&3));
+        assert!(root.contains_local(&1));
+        assert!(!root.contains_local(&2));
+        assert!(scoped.contains_local(&2));
+        assert!(!scoped.contains(&1));
+    }
+
+    #[test]
+    fn indexed_matches_unindexed_under_mutation() {
+        // a small xorshift so the mutation script is reproducible
+        let mut state = 0x2545_f491_u32;
+        let mut next = move |bound: u32| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state % bound
+        };
+
+        let mut indexed = vec![ScopedVec::new_indexed()];
+        let mut plain = vec![ScopedVec::new()];
+
+        for _ in 0..1000 {
+            let target = next(indexed.len() as u32) as usize;
+            let len = plain[target].len() as u32;
+            let at = if len == 0 { 0 } else { next(len) as usize };
+            let val = next(32);
+
+            // runs the same operation against both trees
+            macro_rules! both {
+                ($scope:ident => $op:expr) => {{
+                    let a = { let $scope = &indexed[target]; $op };
+                    let b = { let $scope = &plain[target]; $op };
+                    assert_eq!(a, b);
+                }};
+            }
+
+            match next(40) {
+                0 => {
+                    let new_indexed = indexed[target].scope();
+                    indexed.push(new_indexed);
+                    let new_plain = plain[target].scope();
+                    plain.push(new_plain);
+                }
+                1 => both!(scope => scope.drain_leaves()),
+                2 => {
+                    let modulo = next(4) + 2;
+                    both!(scope => scope.retain_local(|v| v % modulo != 0));
+                }
+                3 => both!(scope => scope.pop()),
+                4 if len > 0 => both!(scope => scope.remove(at)),
+                5 if len > 0 => both!(scope => scope.swap_remove(at)),
+                6 => both!(scope => scope.insert(at, val)),
+                7 => both!(scope => scope.truncate(at)),
+                8 => both!(scope => scope.clear()),
+                9 => both!(scope => scope.drain(at..).collect::<Vec<_>>()),
+                10 => both!(scope => scope.split_off(at)),
+                11 => {
+                    let other = next(indexed.len() as u32) as usize;
+                    indexed[target].append(&indexed[other]);
+                    plain[target].append(&plain[other]);
+                }
+                12 => both!(scope => scope.dedup()),
+                13 => both!(scope => scope.replace(&val, (val + 1) % 32)),
+                14 => both!(scope => scope.replace_every(&val, (val + 1) % 32)),
+                15 => both!(scope => scope.remove_value(&val)),
+                16 if len > 0 => both!(scope => scope.swap(at, len as usize - 1)),
+                17 => both!(scope => scope.update_at(at, |v| *v = (*v + 7) % 32)),
+                18 => both!(scope => scope.for_each_mut(|v| *v = (*v + 1) % 32)),
+                19 => both!(scope => scope.replace_all(|v| v * 3 % 32)),
+                20 => both!(scope => scope.collapse_below(at % 3)),
+                21 => both!(scope => scope.collapse_levels(at % 3 + 1)),
+                22 => both!(scope => scope.take_tree()),
+                23 => both!(scope => scope.truncate_to_len(at)),
+                24 if target > 0 => {
+                    // the only handles to the scope are these and its
+                    // parent's, so dropping them promotes its values
+                    indexed[target].set_promote_on_drop(true);
+                    plain[target].set_promote_on_drop(true);
+                    drop(indexed.swap_remove(target));
+                    drop(plain.swap_remove(target));
+                }
+                _ => both!(scope => scope.push(val)),
+            }
+
+            for (indexed, plain) in indexed.iter().zip(&plain) {
+                assert_eq!(indexed.to_nested_vec(), plain.to_nested_vec());
+                for val in 0..32 {
+                    assert_eq!(indexed.contains(&val), plain.contains(&val));
+                    assert_eq!(indexed.contains_local(&val), plain.contains_local(&val));
+                }
+                indexed.debug_assert_invariants();
+            }
+        }
+
+        indexed[0].debug_assert_invariants();
+    }
+
+    #[test]
+    fn pruned_branches_contribute_nothing() {
+        let root = ScopedVec::new();
+        root.push(1);
+
+        let pruned = root.scope();
+        pruned.push(2);
+        pruned.scope().push(3);
+
+        let kept = root.scope();
+        kept.push(4);
+        let nested_pruned = kept.scope();
+        nested_pruned.push(5);
+        kept.scope().push(6);
+
+        let pruned: Vec<_> = root.iter_pruned(|child| !child.contains_local(&2) && !child.contains_local(&5)).collect();
+        assert_eq!(pruned, vec![&1, &4, &6]);
+    }
+
+    #[test]
+    fn nested_vec_mirrors_shape() {
+        let root = ScopedVec::new();
+        root.push(3);
+        let scoped = root.scope();
+        scoped.push(4);
+        scoped.scope().push(5);
+        root.scope();
+
+        let nested = root.to_nested_vec();
+        assert_eq!(nested, NestedVec {
+            values: vec![3],
+            children: vec![
+                NestedVec {
+                    values: vec![4],
+                    children: vec![NestedVec { values: vec![5], children: vec![] }]
+                },
+                NestedVec::default(),
+            ]
+        });
+
+        let round_tripped = ScopedVec::from_nested(nested.clone());
+        assert_eq!(round_tripped.to_nested_vec(), nested);
+        assert_structure!(round_tripped, [3; [4; [5]], []]);
+    }
+
+    #[test]
+    fn retain_local_leaves_children_untouched() {
+        let root = ScopedVec::new();
+        root.push(1);
+        root.push(2);
+        root.push(3);
+
+        let scoped = root.scope();
+        scoped.push(2);
+        scoped.push(4);
+
+        root.retain_local(|v| v % 2 != 0);
+
+        assert_contents!(root, [1, 3, 2, 4]);
+    }
+
+    #[test]
+    fn dedup_keeps_first_occurrences_across_scopes() {
+        let root = ScopedVec::new_indexed();
+        root.push(1);
+        root.push(2);
+        root.push(1);
+        let child = root.scope();
+        child.push(2);
+        child.push(3);
+        child.push(3);
+        child.scope().push(1);
+        root.scope().push(4);
+
+        root.dedup();
+        assert_structure!(root, [1, 2; [3; []], [4]]);
+        assert!(!child.contains_local(&2));
+
+        root.scope_at_path(&[1]).unwrap().push(13);
+        root.dedup_by(|a, b| a % 10 == b % 10);
+        assert_structure!(root, [1, 2; [3; []], [4]]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn retain_deep_filters_every_scope() {
+        let root = ScopedVec::from(nested!([1, 2; [3, 4; [5, 6]], [7]]));
+        root.retain(|v| *v != 1);
+        assert_structure!(root, [2; [3, 4; [5, 6]], [7]]);
+
+        let mut seen = Vec::new();
+        root.retain_deep(|v| {
+            seen.push(*v);
+            v % 2 == 0
+        });
+        assert_eq!(seen, vec![2, 3, 4, 5, 6, 7]);
+        assert_structure!(root, [2; [4; [6]], []]);
+    }
+
+    #[test]
+    fn swap_children_reorders_iteration() {
+        let root = ScopedVec::new();
+        root.push(1);
+        let scoped1 = root.scope();
+        scoped1.push(2);
+        scoped1.scope().push(3);
+        root.scope().push(4);
+        root.scope().push(5);
+
+        root.swap_children(0, 2);
+        assert_contents!(root, [1, 5, 4, 2, 3]);
+
+        root.swap_children(1, 1);
+        assert_structure!(root, [1; [5], [4], [2; [3]]]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn swap_children_out_of_bounds() {
+        let root: ScopedVec<i32> = ScopedVec::new();
+        root.scope();
+        root.swap_children(0, 1);
+    }
+
+    #[test]
+    fn find_map_finds_deep_value() {
+        let root = ScopedVec::new();
+        root.push("a");
+        let scoped = root.scope();
+        scoped.push("b");
+        let nested_scoped = scoped.scope();
+        nested_scoped.push("42");
+        nested_scoped.push("43");
+
+        assert_eq!(root.find_map(|v| v.parse::<u32>().ok()), Some(42));
+        assert_eq!(root.find_map(|v| v.strip_prefix('z')), None);
+    }
+
+    #[test]
+    fn positions_of_across_scopes() {
+        let root = ScopedVec::new();
+        root.push(1);
+        root.push(2);
+        let scoped = root.scope();
+        scoped.push(1);
+        scoped.scope().push(3);
+        root.scope().push(1);
+
+        assert_eq!(root.positions_of(&1), vec![0, 2, 4]);
+        assert_eq!(root.positions_of(&3), vec![3]);
+        assert_eq!(root.positions_of(&4), vec![]);
+        assert_eq!(scoped.positions_of(&1), vec![0]);
+    }
+
+    #[test]
+    fn compares_against_vecs_and_slices() {
+        let root = ScopedVec::new();
+        root.push(3);
+        root.scope().push(4);
+
+        assert_eq!(root, vec![3, 4]);
+        assert_eq!(vec![3, 4], root);
+        assert_eq!(root, &[3, 4][..]);
+        assert_eq!(&[3, 4][..], root);
+        assert_eq!(root, [3, 4]);
+        assert_ne!(root, [3]);
+        assert_ne!(root, [3, 4, 5]);
+        assert_ne!(root, vec![4, 3]);
+    }
+
+    #[test]
+    fn value_capacity_can_be_reserved_and_released() {
+        let root = ScopedVec::with_capacity(32);
+        assert!(root.capacity() >= 32);
+        assert_eq!(root.scope().capacity(), 0);
+
+        root.push(1);
+        root.shrink_to_fit();
+        assert_eq!(root.capacity(), 1);
+
+        root.reserve(10);
+        assert!(root.capacity() >= 11);
+        assert_eq!(root, [1]);
+    }
+
+    #[test]
+    fn children_capacity_can_be_reserved() {
+        let root = ScopedVec::<i32>::with_children_capacity(16);
+        assert!(root.children_capacity() >= 16);
+
+        let before = root.children_capacity();
+        root.split_for_parallel(16);
+        assert_eq!(root.children_capacity(), before);
+
+        root.reserve_children(64);
+        assert!(root.children_capacity() >= 80);
+        assert_eq!(root.scope().children_capacity(), 0);
+    }
+
+    #[test]
+    fn iter_sample_yields_every_nth_value() {
+        let root = ScopedVec::new();
+        root.push(0);
+        root.push(1);
+        let scoped = root.scope();
+        for v in 2..7 {
+            scoped.push(v);
+        }
+        root.scope().push(7);
+
+        assert_eq!(root.iter_sample(1).copied().collect::<Vec<_>>(), (0..8).collect::<Vec<_>>());
+        assert_eq!(root.iter_sample(3).copied().collect::<Vec<_>>(), vec![0, 3, 6]);
+        assert_eq!(root.iter_sample(10).copied().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "non-zero")]
+    fn iter_sample_rejects_zero_step() {
+        let _ = ScopedVec::<i32>::new().iter_sample(0);
+    }
+
+    #[test]
+    fn panicking_callbacks_leave_the_tree_usable() {
+        let root = ScopedVec::new_indexed();
+        root.push(1);
+        let scoped = root.scope();
+        scoped.push(2);
+        scoped.push(3);
+        scoped.push(4);
+
+        let handle = scoped.clone();
+        let result = std::panic::catch_unwind(move || {
+            handle.retain_local(|v| if *v == 3 { panic!("boom") } else { *v != 2 });
+        });
+        assert!(result.is_err());
+        assert_eq!(root, [1, 2, 3, 4]);
+        root.debug_assert_invariants();
+
+        let result = std::panic::catch_unwind(|| {
+            root.iter_pruned(|_| panic!("boom")).count();
+        });
+        assert!(result.is_err());
+
+        let result = std::panic::catch_unwind(|| {
+            root.find_map(|_| -> Option<()> { panic!("boom") });
+        });
+        assert!(result.is_err());
+
+        let result = std::panic::catch_unwind(|| {
+            root.merge_with(&scoped, |_, _| panic!("boom"));
+        });
+        assert!(result.is_err());
+
+        scoped.push(5);
+        root.push(6);
+        assert_eq!(root, [1, 6, 2, 3, 4, 5]);
+        assert!(scoped.contains_local(&5));
+        root.debug_assert_invariants();
+    }
+
+    /// Run `op` against an indexed tree, expecting it to panic, then
+    /// check the tree was left consistent and still accepts values.
+    fn assert_recovers_from_panic(op: impl FnOnce(&ScopedVec<i32>)) {
+        let root = ScopedVec::new_indexed();
+        root.push_all([1, 2, 2]);
+        let child = root.scope();
+        child.push_all([3, 4, 4]);
+        child.scope().push(5);
+
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| op(&root)));
+        assert!(result.is_err());
+        root.debug_assert_invariants();
+
+        let values = root.to_vec();
+        for val in 0..32 {
+            assert_eq!(root.contains(&val), values.contains(&val));
+        }
+
+        root.push(6);
+        child.push(7);
+        assert!(root.contains(&6) && root.contains(&7));
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn for_each_mut_recovers_from_panics() {
+        assert_recovers_from_panic(|root| root.for_each_mut(|v| if *v == 4 { panic!("boom") } else { *v += 10 }));
+    }
+
+    #[test]
+    fn replace_all_recovers_from_panics() {
+        assert_recovers_from_panic(|root| root.replace_all(|v| if v == 4 { panic!("boom") } else { v + 10 }));
+    }
+
+    #[test]
+    fn update_at_recovers_from_panics() {
+        assert_recovers_from_panic(|root| {
+            root.update_at(3, |v| {
+                *v += 10;
+                panic!("boom")
+            });
+        });
+    }
+
+    #[test]
+    fn retain_deep_recovers_from_panics() {
+        assert_recovers_from_panic(|root| root.retain_deep(|v| if *v == 4 { panic!("boom") } else { v % 2 == 0 }));
+    }
+
+    #[test]
+    fn dedup_doesnt_deadlock_with_appends_or_comparisons() {
+        for _ in 0..50 {
+            let parent = ScopedVec::new();
+            parent.push_all([1, 1, 2]);
+            let child = parent.scope();
+            child.push_all([2, 3]);
+
+            std::thread::scope(|s| {
+                s.spawn(|| (0..50).for_each(|_| parent.dedup()));
+                s.spawn(|| (0..50).for_each(|i| {
+                    child.append(&parent);
+                    let _ = child == parent;
+                    parent.push(i % 3);
+                }));
+            });
+            parent.debug_assert_invariants();
+        }
+    }
+
+    #[test]
+    fn dedup_by_recovers_from_panics() {
+        assert_recovers_from_panic(|root| root.dedup_by(|a, _| if *a == 4 { panic!("boom") } else { true }));
+    }
+
+    #[test]
+    fn try_map_recovers_from_panics() {
+        assert_recovers_from_panic(|root| {
+            let _ = root.try_map(|v| if *v == 4 { panic!("boom") } else { Ok::<_, ()>(*v) });
+        });
+    }
+
+    #[test]
+    fn merge_sorted_merges_sorted_scopes() {
+        let root = ScopedVec::from(nested!([1, 5, 9; [2, 2, 8; [3, 7]], [0, 6]]));
+        assert_eq!(root.merge_sorted(), vec![0, 1, 2, 2, 3, 5, 6, 7, 8, 9]);
+
+        let unsorted = ScopedVec::from(nested!([5, 1; [3]]));
+        let mut merged = unsorted.merge_sorted();
+        assert_eq!(merged, vec![3, 5, 1]);
+        merged.sort_unstable();
+        assert_eq!(merged, vec![1, 3, 5]);
+
+        assert!(ScopedVec::<i32>::new().merge_sorted().is_empty());
+    }
+
+    #[test]
+    fn dedup_scopes_removes_identical_siblings() {
+        let root = ScopedVec::from(nested!([
+            0;
+            [1; [2]],
+            [1; [2], [2]],
+            [1, 2],
+            [3],
+            [1; [2]],
+            [3]
+        ]));
+        root.dedup_scopes();
+
+        assert_structure!(root, [0; [1; [2]], [1, 2], [3]]);
+    }
+
+    #[test]
+    fn collapse_below_compacts_deep_chains() {
+        let root = ScopedVec::new();
+        let mut scopes = vec![root.clone()];
+        for depth in 0..10 {
+            let last = scopes.last().unwrap().clone();
+            last.push(depth);
+            scopes.push(last.scope());
+        }
+        root.scope().push(100);
+        let before: Vec<_> = root.iter().copied().collect();
+
+        root.collapse_below(2);
+
+        assert_eq!(root, before);
+        assert_structure!(root, [0; [1; [2, 3, 4, 5, 6, 7, 8, 9]], [100]]);
+        root.debug_assert_invariants();
+
+        let detached = &mut scopes[5];
+        assert!(detached.is_empty());
+        assert!(detached.is_empty_deep());
+        detached.push(-1);
+        assert_eq!(root, before);
+        assert_eq!(*detached, [-1]);
+    }
+
+    #[test]
+    fn path_from_root_locates_scopes() {
+        let root = ScopedVec::<i32>::new();
+        let first = root.scope();
+        let _ = first.scope();
+        let second = root.scope();
+        let _ = second.scope();
+        let child = second.scope();
+        let grandchild = child.scope();
+
+        assert!(root.path_from_root().is_empty());
+        assert_eq!(child.path_from_root(), vec![1, 1]);
+        assert_eq!(grandchild.path_from_root(), vec![1, 1, 0]);
+        assert!(root.scope_at_path(&child.path_from_root()).unwrap().ptr_eq(&child));
+        assert!(root.scope_at_path(&[]).unwrap().ptr_eq(&root));
+        assert!(root.scope_at_path(&[2]).is_none());
+
+        // with the root and `second` dropped, `child` becomes the topmost
+        // live ancestor
+        drop(root);
+        drop(second);
+        assert!(child.path_from_root().is_empty());
+        assert_eq!(grandchild.path_from_root(), vec![0]);
+
+        child.collapse_below(0);
+        assert!(grandchild.path_from_root().is_empty());
+    }
+
+    #[test]
+    fn truncate_to_len_evicts_from_the_end() {
+        let root = ScopedVec::new_indexed();
+        root.push(1);
+        root.push(2);
+        let scoped = root.scope();
+        scoped.push(3);
+        scoped.scope().push(4);
+        scoped.push(5);
+        root.scope().push(6);
+
+        root.truncate_to_len(10);
+        assert_eq!(root, [1, 2, 3, 5, 4, 6]);
+
+        root.truncate_to_len(4);
+        assert_structure!(root, [1, 2; [3, 5; []], []]);
+        assert!(!root.contains(&6));
+
+        scoped.truncate_to_len(0);
+        assert_eq!(root, [1, 2]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn for_each_scope_applies_to_matching_scopes() {
+        let root = ScopedVec::from(nested!([0, -1; [1; [-1, 2], [3]], [-1; [4; [-1]]]]));
+        let is_temp = |scope: &ScopedVec<i32>| scope.contains_local(&-1);
+
+        let temp = root.collect_scopes(is_temp);
+        assert_eq!(temp.len(), 4);
+        assert!(temp[0].ptr_eq(&root));
+        assert_eq!(temp.iter().map(ScopedVec::path_from_root).collect::<Vec<_>>(),
+            vec![vec![], vec![0, 0], vec![1], vec![1, 0, 0]]);
+
+        let mut visited = 0;
+        root.for_each_scope(is_temp, |scope| {
+            visited += 1;
+            scope.retain_local(|_| false);
+        });
+        assert_eq!(visited, 4);
+        assert_structure!(root, [; [1; [], [3]], [; [4; []]]]);
+
+        let mut leaves = 0;
+        root.for_each_scope(ScopedVec::has_no_children, |_| leaves += 1);
+        assert_eq!(leaves, 3);
+    }
+
+    #[test]
+    fn scope_offsets_map_to_flattened_positions() {
+        let root = ScopedVec::from(nested!([0, 1; [2; [], [3, 4]], [5]]));
+        let offsets: Vec<_> = root.iter_scope_offsets().collect();
+
+        assert_eq!(offsets, vec![
+            (vec![], 0, 2),
+            (vec![0], 2, 1),
+            (vec![0, 0], 3, 0),
+            (vec![0, 1], 3, 2),
+            (vec![1], 5, 1),
+        ]);
+
+        let flat: Vec<_> = root.iter().copied().collect();
+        let mut expected_start = 0;
+        for (path, start, len) in offsets {
+            assert_eq!(start, expected_start);
+            let scope = root.scope_at_path(&path).unwrap();
+            crate::testing::assert_local(&scope, &flat[start..start + len]);
+            expected_start += len;
+        }
+        assert_eq!(expected_start, flat.len());
+    }
+
+    #[test]
+    fn handles_can_be_shared_by_reference() {
+        let root = ScopedVec::new();
+        let scoped = root.scope();
+
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                let root = &root;
+                let scoped = &scoped;
+                s.spawn(move || {
+                    root.push(i);
+                    scoped.push(i * 10);
+                    scoped.scope().push(i * 100);
+                });
+            }
+        });
+
+        let mut values: Vec<_> = root.iter().copied().collect();
+        values.sort_unstable();
+        assert_eq!(values, vec![0, 0, 0, 1, 2, 3, 10, 20, 30, 100, 200, 300]);
+        assert_eq!(scoped.child_handles().len(), 4);
+    }
+
+    #[test]
+    fn clear_descendants_keeps_own_values() {
+        let root = ScopedVec::from(nested!([1, 2; [3; [4]], [5]]));
+        let scoped = root.scope_at_path(&[0]).unwrap();
+
+        root.clear_descendants(false);
+        assert_structure!(root, [1, 2; [; []], []]);
+        scoped.push(6);
+        assert_eq!(root, [1, 2, 6]);
+
+        root.clear_descendants(true);
+        assert_structure!(root, [1, 2]);
+        assert!(scoped.is_empty());
+        assert!(scoped.is_empty_deep());
+        assert!(scoped.path_from_root().is_empty());
+
+        scoped.push(7);
+        assert_eq!(root, [1, 2]);
+    }
+
+    #[test]
+    fn dead_weak_references_are_skipped_and_purged() {
+        let alive = Arc::new("alive");
+        let dropped = Arc::new("dropped");
+
+        let root = ScopedVec::new();
+        root.push_downgraded(&alive);
+        root.push_downgraded(&dropped);
+        let scoped = root.scope();
+        scoped.push_downgraded(&dropped);
+        scoped.push_downgraded(&alive);
+
+        assert_eq!(root.iter_upgraded().count(), 4);
+        drop(dropped);
+
+        let upgraded: Vec<_> = root.iter_upgraded().map(|v| *v).collect();
+        assert_eq!(upgraded, vec!["alive", "alive"]);
+        assert_eq!(root.iter().count(), 4);
+
+        assert_eq!(root.purge_dead(), 1);
+        assert_eq!(root.iter().count(), 3);
+        assert_eq!(root.purge_dead_deep(), 1);
+        assert_eq!(root.purge_dead_deep(), 0);
+        assert_eq!(root.iter().count(), 2);
+    }
+
+    #[test]
+    fn relaxed_iteration_lets_writers_progress() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+
+        // returns true if the writer managed to push before the walk
+        // called `finish`, which it does before releasing any locks
+        fn writer_progresses(walk: impl FnOnce(&ScopedVec<i32>, &dyn Fn(), &dyn Fn()) + Send) -> bool {
+            let root = ScopedVec::new();
+            for v in 0..10 {
+                root.push(v);
+            }
+            root.scope().push(10);
+            let done = AtomicBool::new(false);
+
+            std::thread::scope(|s| {
+                let writer = s.spawn(|| {
+                    std::thread::sleep(Duration::from_millis(30));
+                    root.push(11);
+                    !done.load(Ordering::SeqCst)
+                });
+
+                walk(
+                    &root,
+                    &|| std::thread::sleep(Duration::from_millis(20)),
+                    &|| done.store(true, Ordering::SeqCst)
+                );
+                writer.join().unwrap()
+            })
+        }
+
+        assert!(writer_progresses(|root, slow, finish| {
+            let mut seen = Vec::new();
+            root.for_each_relaxed(2, |v| {
+                slow();
+                seen.push(v);
+            });
+            finish();
+            assert_eq!(seen, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 11, 10]);
+        }));
+
+        assert!(!writer_progresses(|root, slow, finish| {
+            let mut iter = root.iter();
+            iter.by_ref().for_each(|_| slow());
+            finish();
+            drop(iter);
+        }));
+    }
+
+    #[test]
+    fn try_fold_stops_at_the_first_error() {
+        let root = ScopedVec::from(nested!([1, 2; [3; [4]], [5]]));
+
+        assert_eq!(root.try_fold(0, |acc, v| Ok::<_, ()>(acc + v)), Ok(15));
+
+        let mut visited = Vec::new();
+        let result = root.try_fold(0, |acc, v| {
+            visited.push(*v);
+            if *v == 3 { Err(format!("bad value {}", v)) } else { Ok(acc + v) }
+        });
+        assert_eq!(result, Err("bad value 3".to_string()));
+        assert_eq!(visited, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn replace_subtree_at_path_swaps_branches() {
+        let root = ScopedVec::from(nested!([0; [1; [2], [3]], [4]]));
+        let fresh = ScopedVec::from(nested!([30; [31]]));
+
+        let old = root.replace_subtree_at_path(&[0, 1], fresh.clone()).unwrap().unwrap();
+        assert_structure!(root, [0; [1; [2], [30; [31]]], [4]]);
+        assert_eq!(old, [3]);
+        assert!(old.path_from_root().is_empty());
+        assert_eq!(fresh.path_from_root(), vec![0, 1]);
+
+        assert!(root.replace_subtree_at_path(&[0, 1], fresh.clone()).unwrap().is_none());
+
+        assert_eq!(root.replace_subtree_at_path(&[], ScopedVec::new()).unwrap_err(), ScopedVecError::EmptyPath);
+        assert_eq!(root.replace_subtree_at_path(&[0, 5], ScopedVec::new()).unwrap_err(),
+            ScopedVecError::InvalidPath { path: vec![0, 5], depth: 1 });
+        assert_eq!(root.replace_subtree_at_path(&[3, 0], ScopedVec::new()).unwrap_err(),
+            ScopedVecError::InvalidPath { path: vec![3, 0], depth: 0 });
+        assert_eq!(root, [0, 1, 2, 30, 31, 4]);
+    }
+
+    #[test]
+    fn replace_subtree_at_path_rejects_cycles() {
+        let root = ScopedVec::from(nested!([0; [1; [2]]]));
+        let child = root.scope_at_path(&[0]).unwrap();
+
+        assert_eq!(child.replace_subtree_at_path(&[0], root.clone()).unwrap_err(), ScopedVecError::Cycle);
+        assert_eq!(root.replace_subtree_at_path(&[0, 0], root.clone()).unwrap_err(), ScopedVecError::Cycle);
+
+        let detached = root.drain_scopes_where(|_| true).remove(0);
+        assert_eq!(detached.replace_subtree_at_path(&[0], detached.clone()).unwrap_err(), ScopedVecError::Cycle);
+
+        assert_structure!(root, [0]);
+        assert_structure!(detached, [1; [2]]);
+        assert!(detached.path_from_root().is_empty());
+        root.debug_assert_invariants();
+        detached.debug_assert_invariants();
+    }
+
+    #[test]
+    fn replace_subtree_at_path_rejects_attached_scopes() {
+        let root = ScopedVec::from(nested!([0; [1], [2]]));
+        let other = ScopedVec::from(nested!([; [3]]));
+        let attached = other.scope_at_path(&[0]).unwrap();
+
+        assert_eq!(root.replace_subtree_at_path(&[0], attached.clone()).unwrap_err(), ScopedVecError::Attached);
+        assert_eq!(attached.path_from_root(), vec![0]);
+
+        let fresh = ScopedVec::from(nested!([4]));
+        assert!(root.replace_subtree_at_path(&[0], fresh.clone()).unwrap().is_some());
+        assert_eq!(root.replace_subtree_at_path(&[1], fresh.clone()).unwrap_err(), ScopedVecError::Attached);
+
+        assert_structure!(root, [0; [4], [2]]);
+        assert_structure!(other, [; [3]]);
+        root.dedup();
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn try_map_reports_the_failing_value() {
+        let root = ScopedVec::from(nested!([
+            "1", "2";
+            ["3"; ["4", "x"]],
+            ["5"]
+        ]));
+
+        let parsed = root.try_map(|v| v.parse::<i32>());
+        let err = parsed.unwrap_err();
+        assert_eq!(err.path, vec![0, 0]);
+        assert_eq!(err.index, 1);
+        assert!(err.to_string().starts_with("failed to map value 1 of scope at path [0, 0]: "));
+
+        let valid = root.scope_at_path(&[1]).unwrap();
+        let mapped = valid.try_map(|v| v.parse::<i32>()).unwrap();
+        assert_eq!(mapped.to_nested_vec(), valid.map(|v| v.parse::<i32>().unwrap()).to_nested_vec());
+
+        let lens = root.map(|v| v.len() as i32 * 10);
+        assert_structure!(lens, [10, 10; [10; [10, 10]], [10]]);
+    }
+
+    #[test]
+    fn into_iter_moves_values_out() {
+        let root = ScopedVec::from(nested!([
+            "a".to_string();
+            ["b".to_string(); ["c".to_string()]],
+            ["d".to_string()]
+        ]));
+        let shared = root.scope_at_path(&[0, 0]).unwrap();
+
+        let mut values = Vec::new();
+        for val in root {
+            values.push(val);
+        }
+        assert_eq!(values, vec!["a", "b", "c", "d"]);
+
+        // the shared scope was cloned from rather than emptied
+        assert_eq!(shared, vec!["c".to_string()]);
+    }
+
+    #[test]
+    fn zip_with_combines_matching_trees() {
+        let expected = ScopedVec::from(nested!([10; [20, 30; [40]], [50]]));
+        let actual = ScopedVec::from(nested!([11; [19, 30; [45]], [50]]));
+
+        let diff = expected.zip_with(&actual, |e, a| a - e).unwrap();
+        assert_structure!(diff, [1; [-1, 0; [5]], [0]]);
+        assert!(expected.same_structure(&actual));
+        assert!(expected.same_structure(&diff));
+
+        let extra_value = ScopedVec::from(nested!([11; [19, 30; [45, 46]], [50]]));
+        let err = expected.zip_with(&extra_value, |e, a| a - e).unwrap_err();
+        assert_eq!(err.path, vec![0, 0]);
+        assert_eq!(err.kind, MismatchKind::LocalLength { left: 1, right: 2 });
+        assert!(!expected.same_structure(&extra_value));
+
+        let extra_child = ScopedVec::from(nested!([11; [19, 30; [45], []], [50]]));
+        let err = expected.zip_with(&extra_child, |e, a| a - e).unwrap_err();
+        assert_eq!(err.path, vec![0]);
+        assert_eq!(err.kind, MismatchKind::ChildCount { left: 1, right: 2 });
+        assert_eq!(err.to_string(), "scopes at path [0] have 1 and 2 children");
+    }
+
+    #[test]
+    fn histogram_buckets_by_key() {
+        #[derive(Debug)]
+        struct Event {
+            kind: &'static str,
+            latency: u32,
+        }
+
+        let root = ScopedVec::new();
+        root.push(Event { kind: "connect", latency: 3 });
+        let scoped = root.scope();
+        scoped.push(Event { kind: "read", latency: 1 });
+        scoped.scope().push(Event { kind: "read", latency: 8 });
+        root.scope().push(Event { kind: "connect", latency: 5 });
+        root.scope().push(Event { kind: "close", latency: 1 });
+
+        let by_kind = root.histogram(|e| e.kind);
+        assert_eq!(by_kind.len(), 3);
+        assert_eq!(by_kind["connect"], 2);
+        assert_eq!(by_kind["read"], 2);
+        assert_eq!(by_kind["close"], 1);
+
+        let slow = scoped.histogram(|e| e.latency > 4);
+        assert_eq!(slow[&true], 1);
+        assert_eq!(slow[&false], 1);
+    }
+
+    #[test]
+    fn depth_runs_differ_from_depth_groups() {
+        let root = ScopedVec::from(nested!([1, 2; [3, 4; [5]], [], [6; [7, 8]]]));
+
+        let runs: Vec<_> = root.iter_by_depth_runs().collect();
+        assert_eq!(runs, vec![
+            (0, vec![1, 2]),
+            (1, vec![3, 4]),
+            (2, vec![5]),
+            (1, vec![6]),
+            (2, vec![7, 8]),
+        ]);
+
+        assert_eq!(root.collect_by_depth(), vec![vec![1, 2], vec![3, 4, 6], vec![5, 7, 8]]);
+    }
+
+    #[test]
+    fn reference_counts_track_handles() {
+        let root = ScopedVec::<i32>::new();
+        assert_eq!(root.strong_count(), 1);
+        assert_eq!(root.weak_count(), 0);
+
+        let scoped = root.scope();
+        let handle = scoped.clone();
+        assert_eq!(scoped.strong_count(), 3);
+        assert_eq!(root.weak_count(), 1);
+
+        drop(handle);
+        let _ = root.scope();
+        assert_eq!(scoped.strong_count(), 2);
+        assert_eq!(root.weak_count(), 2);
+
+        drop(root);
+        assert_eq!(scoped.strong_count(), 1);
+    }
+
+    #[test]
+    fn collapse_levels_preserves_order() {
+        let tree = || ScopedVec::from(nested!([0; [1; [2; [3]], [4]], [5; [6]]]));
+
+        let root = tree();
+        let removed = root.scope_at_path(&[0]).unwrap();
+        root.collapse_levels(1);
+        assert_eq!(root, [0, 1, 2, 3, 4, 5, 6]);
+        assert_structure!(root, [0, 1; [2; [3]], [4], [5], [6]]);
+        assert!(removed.is_empty());
+        assert!(removed.is_empty_deep());
+        assert!(removed.path_from_root().is_empty());
+        assert_eq!(root.scope_at_path(&[1]).unwrap().path_from_root(), vec![1]);
+        root.debug_assert_invariants();
+
+        let root = tree();
+        root.collapse_levels(2);
+        assert_eq!(root, [0, 1, 2, 3, 4, 5, 6]);
+        assert_structure!(root, [0, 1, 2; [3], [4, 5, 6]]);
+
+        let root = tree();
+        root.collapse_levels(0);
+        assert_eq!(root.to_nested_vec(), tree().to_nested_vec());
+        root.collapse_levels(10);
+        assert_structure!(root, [0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn top_k_matches_sorting_everything() {
+        let mut state = 0x9e37_79b9_u32;
+        let mut next = move |bound: u32| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state % bound
+        };
+
+        for _ in 0..20 {
+            let root = ScopedVec::new();
+            let mut scopes = vec![root.clone()];
+
+            for _ in 0..next(60) {
+                let target = next(scopes.len() as u32) as usize;
+                if next(4) == 0 {
+                    let child = scopes[target].scope();
+                    scopes.push(child);
+                } else {
+                    scopes[target].push(next(50) as i32 - 25);
+                }
+            }
+
+            let mut sorted: Vec<i32> = root.iter().copied().collect();
+            sorted.sort_unstable();
+            let total = sorted.len();
+
+            for k in [0, 1, 3, 10, total, total + 5] {
+                let bottom: Vec<_> = sorted.iter().copied().take(k).collect();
+                let top: Vec<_> = sorted.iter().rev().copied().take(k).collect();
+
+                assert_eq!(root.bottom_k(k), bottom);
+                assert_eq!(root.top_k(k), top);
+
+                assert_eq!(root.top_k_by_key(k, |v| -v), bottom);
+            }
+        }
+    }
+
+    #[test]
+    fn find_scope_returns_first_match_depth_first() {
+        let root = ScopedVec::from_nested(nested!([0; [1, 2; [3, 4, 5]], [6, 7, 8]]));
+        let local_len = |scope: &ScopedVec<i32>| lock::read(&scope.node.inner).values.len();
+
+        let found = root.find_scope(|scope| local_len(scope) >= 3).unwrap();
+        assert!(found.ptr_eq(&root.scope_at_path(&[0, 0]).unwrap()));
+
+        assert!(root.find_scope(|scope| local_len(scope) >= 1).unwrap().ptr_eq(&root));
+        assert!(root.find_scope(|scope| local_len(scope) > 3).is_none());
+
+        let mut visited = 0;
+        root.find_scope(|_| {
+            visited += 1;
+            visited == 2
+        });
+        assert_eq!(visited, 2);
+    }
+
+    #[test]
+    fn take_tree_keeps_structure() {
+        let root = ScopedVec::from_nested(nested!([0; [1; [2]], [3]]));
+        let child = root.scope_at_path(&[1]).unwrap();
+
+        assert_eq!(root.take_tree(), vec![
+            (vec![], vec![0]),
+            (vec![0], vec![1]),
+            (vec![0, 0], vec![2]),
+            (vec![1], vec![3]),
+        ]);
+        assert_structure!(root, [; [; []], []]);
+
+        child.push(4);
+        assert_contents!(root, [4]);
+    }
+
+    #[test]
+    fn take_tree_loses_nothing_to_concurrent_pushes() {
+        let root = ScopedVec::new();
+        let scopes = [root.clone(), root.scope(), root.scope().scope()];
+        let total = 20_000;
+
+        let mut taken: Vec<i32> = std::thread::scope(|s| {
+            let pusher = s.spawn(|| {
+                for i in 0..total {
+                    scopes[i as usize % scopes.len()].push(i);
+                }
+            });
+
+            let mut taken = Vec::new();
+            while !pusher.is_finished() {
+                taken.extend(root.take_tree().into_iter().flat_map(|(_, values)| values));
+            }
+            taken
+        });
+        taken.extend(root.take_tree().into_iter().flat_map(|(_, values)| values));
+
+        taken.sort_unstable();
+        assert_eq!(taken, (0..total).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn try_operations_report_why_they_failed() {
+        let root = ScopedVec::new();
+        root.push(1);
+
+        {
+            let _held = lock::write(&root.node.inner);
+            assert_eq!(root.try_push(2), Err(TryPushError { value: 2, error: ScopedVecError::WouldBlock }));
+            assert_eq!(root.try_iter().err(), Some(ScopedVecError::WouldBlock));
+            assert_eq!(root.try_scope().err(), Some(ScopedVecError::WouldBlock));
+        }
+
+        root.try_push(2).unwrap();
+        root.try_scope().unwrap().try_push(3).unwrap();
+        assert_eq!(root.try_iter().unwrap().copied().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        #[cfg(not(feature = "parking_lot"))]
+        {
+            let poisoned = root.clone();
+            let _ = std::thread::spawn(move || {
+                let _held = lock::write(&poisoned.node.inner);
+                panic!("poison the lock");
+            }).join();
+
+            assert_eq!(root.try_push(4).unwrap_err().error, ScopedVecError::Poisoned);
+            assert_eq!(root.try_iter().err(), Some(ScopedVecError::Poisoned));
+        }
+    }
+
+    #[test]
+    fn errors_compose_with_boxed_errors() {
+        fn assert_bounds<E: std::error::Error + Send + Sync + 'static>() {}
+        assert_bounds::<ScopedVecError>();
+        assert_bounds::<TryPushError<i32>>();
+
+        fn push_one(scope: &ScopedVec<i32>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            scope.try_push(1)?;
+            Ok(())
+        }
+
+        let root = ScopedVec::new();
+        push_one(&root).unwrap();
+
+        let _held = lock::read(&root.node.inner);
+        let err = push_one(&root).unwrap_err();
+        assert_eq!(err.to_string(), "failed to push value: operation would block");
+    }
+
+    #[test]
+    fn retain_scopes_reports_every_removed_scope() {
+        let root = ScopedVec::from_nested(nested!([0; [1; [2; [3]], [4]], [5; [6]], [7]]));
+        let removed = root.scope_at_path(&[0, 0]).unwrap();
+
+        let mut visited = Vec::new();
+        let count = root.retain_scopes_reporting(|scope| {
+            let first = *scope.iter().next().unwrap();
+            visited.push(first);
+            first != 2 && first != 5
+        });
+
+        assert_eq!(count, 4);
+        assert_eq!(visited, vec![1, 2, 4, 5, 7]);
+        assert_structure!(root, [0; [1; [4]], [7]]);
+        assert!(removed.path_from_root().is_empty());
+
+        root.retain_scopes(|_| true);
+        assert_structure!(root, [0; [1; [4]], [7]]);
+    }
+
+    #[test]
+    fn iter_timeout_gives_up_on_held_locks() {
+        use std::time::{Duration, Instant};
+
+        let root = ScopedVec::new();
+        root.push(1);
+        root.scope().push(2);
+
+        {
+            let _held = lock::write(&root.node.children);
+            let start = Instant::now();
+            assert_eq!(root.iter_timeout(Duration::from_millis(20)).err(), Some(ScopedVecError::Timeout));
+            assert!(start.elapsed() >= Duration::from_millis(20));
+        }
+
+        std::thread::scope(|s| {
+            let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+            let root = &root;
+            s.spawn(move || {
+                let _held = lock::write(&root.node.inner);
+                locked_tx.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(10));
+            });
+            locked_rx.recv().unwrap();
+
+            let iter = root.iter_timeout(Duration::from_secs(10)).unwrap();
+            assert_eq!(iter.copied().collect::<Vec<_>>(), vec![1, 2]);
+        });
+    }
+
+    #[test]
+    fn ordering_matches_flattened_contents() {
+        let trees = [
+            nested!([1, 2; [3]]),
+            nested!([1; [2], [3]]),
+            nested!([; [1, 2, 3, 4]]),
+            nested!([1, 2]),
+            nested!([1, 3]),
+            nested!([]),
+            nested!([; [], [0]]),
+        ];
+
+        for a in &trees {
+            for b in &trees {
+                let (left, right) = (ScopedVec::from_nested(a.clone()), ScopedVec::from_nested(b.clone()));
+                let flatten = |tree: &ScopedVec<i32>| tree.iter().copied().collect::<Vec<_>>();
+
+                assert_eq!(left.cmp(&right), flatten(&left).cmp(&flatten(&right)));
+                assert_eq!(left.partial_cmp(&right), flatten(&left).partial_cmp(&flatten(&right)));
+                assert_eq!(left == right, flatten(&left) == flatten(&right));
+            }
+        }
+    }
+
+    #[test]
+    fn comparing_against_a_descendant_doesnt_deadlock() {
+        let root = ScopedVec::from_nested(nested!([1; [1; [1]]]));
+        let child = root.scope_at_path(&[0]).unwrap();
+
+        assert!(root > child);
+        assert!(child < root);
+        assert_eq!(root.cmp(&root), std::cmp::Ordering::Equal);
+        assert_eq!(child.scope_at_path(&[0]).unwrap(), ScopedVec::from_nested(nested!([1])));
+
+        let nan = ScopedVec::new();
+        nan.push(f64::NAN);
+        assert_eq!(nan.partial_cmp(&nan), None);
+        assert!(nan != nan);
+    }
+
+    #[test]
+    fn deep_eq_compares_structure_and_values() {
+        let tree = || ScopedVec::from_nested(nested!([1; [2, 3], [4]]));
+        let (left, right) = (tree(), tree());
+        assert!(left.deep_eq(&right));
+        assert!(left.deep_eq(&left));
+
+        let reshaped = ScopedVec::from_nested(nested!([1, 2; [3, 4]]));
+        assert!(left == reshaped);
+        assert!(!left.deep_eq(&reshaped));
+
+        let extra_scope = ScopedVec::from_nested(nested!([1; [2, 3], [4], []]));
+        assert!(left == extra_scope);
+        assert!(!left.deep_eq(&extra_scope));
+
+        right.scope_at_path(&[1]).unwrap().push(5);
+        assert!(!left.deep_eq(&right));
+    }
+
+    #[test]
+    fn split_at_depth_covers_every_value() {
+        let root = ScopedVec::from_nested(nested!([0; [1; [2; [3]], [4]], [5; [6]]]));
+
+        let (top, below) = root.split_at_depth(1);
+        assert_structure!(top, [0; [1], [5]]);
+        assert_eq!(below.iter().map(ScopedVec::to_nested_vec).collect::<Vec<_>>(), vec![
+            nested!([2; [3]]),
+            nested!([4]),
+            nested!([6]),
+        ]);
+        assert!(below.iter().all(|scope| scope.path_from_root().is_empty()));
+
+        let mut all: Vec<_> = top.iter().chain(below.iter().flat_map(ScopedVec::iter)).copied().collect();
+        all.sort_unstable();
+        assert_eq!(all, root.iter().copied().collect::<Vec<_>>());
+
+        let (top, below) = root.split_at_depth(10);
+        assert!(top.deep_eq(&root));
+        assert!(below.is_empty());
+        assert_structure!(root, [0; [1; [2; [3]], [4]], [5; [6]]]);
+    }
+
+    #[test]
+    fn count_per_scope_locates_matches() {
+        let root = ScopedVec::from_nested(nested!([1, 3; [2, 4, 6; [8]], [5, 7]]));
+
+        assert_eq!(root.count_per_scope(|v| v % 2 == 0), vec![
+            (vec![], 0),
+            (vec![0], 3),
+            (vec![0, 0], 1),
+            (vec![1], 0),
+        ]);
+    }
+
+    #[test]
+    fn drain_scopes_where_hands_back_subtrees() {
+        let root = ScopedVec::from(nested!([0; [1, 2, 3; [4, 5, 6]], [7; [8, 9, 10], [11]]]));
+        let drained = root.drain_scopes_where(|scope| lock::read(&scope.node.inner).values.len() >= 3);
+
+        assert_eq!(drained.len(), 2);
+        assert_structure!(drained[0], [1, 2, 3; [4, 5, 6]]);
+        assert_structure!(drained[1], [8, 9, 10]);
+        assert!(drained.iter().all(|scope| scope.path_from_root().is_empty()));
+        assert_structure!(root, [0; [7; [11]]]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn clone_from_shares_the_source_scope() {
+        let root = ScopedVec::new();
+        let source = root.scope();
+        source.push(1);
+
+        let mut handle = root.scope();
+        handle.push(2);
+        handle.clone_from(&source);
+        assert!(handle.ptr_eq(&source));
+        assert_eq!(source.strong_count(), 3);
+
+        handle.push(3);
+        assert_eq!(source, [1, 3]);
+        assert_structure!(root, [; [1, 3], [2]]);
+
+        handle.clone_from(&source);
+        assert_eq!(source.strong_count(), 3);
+    }
+
+    #[test]
+    fn iter_postorder_yields_children_first() {
+        let root = ScopedVec::from(nested!([1, 2; [3; [4], [5]], [6]]));
+        assert_eq!(root.iter().copied().collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(root.iter_postorder().copied().collect::<Vec<_>>(), vec![4, 5, 3, 6, 1, 2]);
+    }
+
+    #[test]
+    fn update_at_modifies_a_nested_value() {
+        let root = ScopedVec::from(nested!([1; [2; [3, 4]], [5]]));
+        assert!(root.update_at(3, |v| *v *= 10));
+        assert!(!root.update_at(5, |_| panic!("index 5 is out of bounds")));
+
+        assert_eq!(root.iter().copied().collect::<Vec<_>>(), vec![1, 2, 3, 40, 5]);
+        assert!(root.scope_at_path(&[0]).unwrap().update_at(0, |v| *v = 20));
+        assert_structure!(root, [1; [20; [3, 40]], [5]]);
+    }
+
+    #[test]
+    fn replace_all_rebuilds_every_value() {
+        let root = ScopedVec::new_indexed();
+        root.push(String::from("a"));
+        let child = root.scope();
+        child.push(String::from("b"));
+        child.scope().push(String::from("c"));
+
+        root.replace_all(|s| s + "!");
+
+        assert_structure!(root, [String::from("a!"); [String::from("b!"); [String::from("c!")]]]);
+        assert!(root.contains(&String::from("a!")));
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn structural_hash_ignores_values() {
+        let root = ScopedVec::from(nested!([1; [2, 3], [4; [5]]]));
+        let hash = root.structural_hash();
+        assert_eq!(ScopedVec::from(nested!([6; [7, 8], [9; [10]]])).structural_hash(), hash);
+
+        root.replace_all(|v| v * 2);
+        assert_eq!(root.structural_hash(), hash);
+
+        root.scope_at_path(&[1, 0]).unwrap().scope();
+        assert_ne!(root.structural_hash(), hash);
+        assert_ne!(ScopedVec::from(nested!([1; [2], [3, 4; [5]]])).structural_hash(), hash);
+    }
+
+    #[test]
+    fn remove_value_takes_the_first_match() {
+        let root = ScopedVec::new_indexed();
+        root.push(1);
+        let child = root.scope();
+        child.push(2);
+        child.push(3);
+        root.scope().push(2);
+
+        assert_eq!(root.remove_value(&2), Some(2));
+        assert_structure!(root, [1; [3], [2]]);
+        assert_eq!(root.remove_value(&2), Some(2));
+        assert_eq!(root.remove_value(&2), None);
+        assert_structure!(root, [1; [3], []]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn replace_substitutes_matching_values() {
+        let root = ScopedVec::new_indexed();
+        root.push("idle");
+        let child = root.scope();
+        child.push("running");
+        child.scope().push("running");
+        root.scope().push("idle");
+
+        assert!(root.replace(&"running", "done"));
+        assert_structure!(root, ["idle"; ["done"; ["running"]], ["idle"]]);
+        assert!(!root.replace(&"failed", "done"));
+
+        assert_eq!(root.replace_every(&"idle", "queued"), 2);
+        assert_eq!(root.replace_every(&"idle", "queued"), 0);
+        assert_structure!(root, ["queued"; ["done"; ["running"]], ["queued"]]);
+        assert!(!root.contains_local(&"idle"));
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn swap_reorders_local_values() {
+        let root = ScopedVec::new_with_provenance();
+        root.push_all(1..=3);
+        root.scope().push(4);
+
+        root.swap(0, 2);
+        root.swap(1, 1);
+        assert_structure!(root, [3, 2, 1; [4]]);
+
+        let oob = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| root.swap(0, 3)));
+        assert!(oob.is_err());
+        assert_structure!(root, [3, 2, 1; [4]]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn insert_places_values_among_local_values() {
+        let root = ScopedVec::new_indexed();
+        root.push(1);
+        root.push(3);
+        root.scope().push(5);
+
+        root.insert(1, 2);
+        root.insert(3, 4);
+        root.insert(0, 0);
+        assert_structure!(root, [0, 1, 2, 3, 4; [5]]);
+        assert!(root.contains_local(&4));
+
+        let oob = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| root.insert(6, 6)));
+        assert!(oob.is_err());
+        assert!(!root.contains(&6));
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn drain_takes_a_range_of_local_values() {
+        let root = ScopedVec::new_indexed();
+        for v in 0..6 {
+            root.push(v);
+        }
+        root.scope().push(6);
+
+        let mut drained = root.drain(1..3);
+        root.push(7);
+        assert_eq!(drained.next(), Some(1));
+        assert_eq!(drained.collect::<Vec<_>>(), vec![2]);
+        assert!(!root.contains(&1));
+
+        assert_eq!(root.drain(2..=3).collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(root.drain(..).collect::<Vec<_>>(), vec![0, 3, 7]);
+        assert_structure!(root, [; [6]]);
+
+        let oob = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| root.drain(..1)));
+        assert!(oob.is_err());
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn append_moves_local_values_between_scopes() {
+        let root = ScopedVec::new_with_provenance();
+        root.push(1);
+        let left = root.scope();
+        let right = root.scope();
+        right.push(2);
+        right.push(3);
+        right.scope().push(4);
+
+        left.append(&right);
+        right.append(&left);
+        left.append(&left);
+        assert_structure!(root, [1; [], [2, 3; [4]]]);
+
+        left.append(&right);
+        root.append(&left);
+        assert_structure!(root, [1, 2, 3; [], [; [4]]]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn split_off_returns_the_local_tail() {
+        let root = ScopedVec::new_with_provenance();
+        for v in 0..4 {
+            root.push(v);
+        }
+        root.scope().push(4);
+
+        assert_eq!(root.split_off(2), vec![2, 3]);
+        assert_eq!(root.split_off(2), Vec::<i32>::new());
+        assert_structure!(root, [0, 1; [4]]);
+
+        let oob = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| root.split_off(3)));
+        assert!(oob.is_err());
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn clear_and_clear_all_keep_the_shape() {
+        let root = ScopedVec::from(nested!([1, 2; [3; [4]], [5]]));
+        let leaf = root.scope_at_path(&[0, 0]).unwrap();
+
+        root.clear();
+        assert_structure!(root, [; [3; [4]], [5]]);
+
+        root.clear_all();
+        assert_structure!(root, [; [; []], []]);
+        assert!(root.is_empty_deep());
+
+        leaf.push(6);
+        assert_eq!(root, [6]);
+    }
+
+    #[test]
+    fn truncate_rolls_back_local_values() {
+        let root = ScopedVec::new_with_provenance();
+        root.push(1);
+        let checkpoint = root.len();
+        root.push(2);
+        root.push(3);
+        root.scope().push(4);
+
+        root.truncate(checkpoint);
+        assert_structure!(root, [1; [4]]);
+        root.truncate(5);
+        assert_structure!(root, [1; [4]]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn swap_remove_moves_the_last_value_into_place() {
+        let root = ScopedVec::new_with_provenance();
+        for v in 1..=4 {
+            root.push(v);
+        }
+        root.scope().push(5);
+
+        assert_eq!(root.swap_remove(0), 1);
+        assert_eq!(root.swap_remove(2), 3);
+        assert_structure!(root, [4, 2; [5]]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn remove_takes_a_local_value_by_index() {
+        let root = ScopedVec::new_with_provenance();
+        root.push(1);
+        root.push(2);
+        root.push(3);
+        root.scope().push(4);
+
+        assert_eq!(root.remove(1), 2);
+        assert_local!(root, [1, 3]);
+
+        let oob = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| root.remove(2)));
+        assert!(oob.is_err());
+        assert_structure!(root, [1, 3; [4]]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn push_all_and_extend_from_slice_append_in_order() {
+        let root = ScopedVec::new_indexed();
+        let child = root.scope();
+        root.push(0);
+        root.push_all(1..4);
+        child.extend_from_slice(&[4, 5]);
+
+        assert_structure!(root, [0, 1, 2, 3; [4, 5]]);
+        assert!(child.contains_local(&5));
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn get_reads_local_values_by_index() {
+        let root = ScopedVec::from(nested!([String::from("a"), String::from("b"); [String::from("c")]]));
+
+        let b = root.get(1).unwrap();
+        assert_eq!(b.len(), 1);
+        assert_eq!(format!("{:?}", b), "\"b\"");
+        drop(b);
+
+        assert!(root.get(2).is_none());
+        assert_eq!(root.get_cloned(0), Some(String::from("a")));
+        assert_eq!(root.get_cloned(2), None);
+        assert_eq!(root.scope_at_path(&[0]).unwrap().get_cloned(0), Some(String::from("c")));
+    }
+
+    #[test]
+    fn first_last_and_last_deep() {
+        let root = ScopedVec::from(nested!([1, 2; [3; [4], []], [; []]]));
+        assert_eq!(root.first().map(|v| *v), Some(1));
+        assert_eq!(root.last().map(|v| *v), Some(2));
+        assert_eq!(root.last_deep(), Some(4));
+
+        let empty = root.scope_at_path(&[1]).unwrap();
+        assert!(empty.first().is_none());
+        assert!(empty.last().is_none());
+        assert_eq!(empty.last_deep(), None);
+
+        empty.scope().push(5);
+        assert_eq!(root.last_deep(), Some(5));
+    }
+
+    #[test]
+    fn pop_only_takes_local_values() {
+        let root = ScopedVec::new_indexed();
+        root.push(1);
+        root.push(2);
+        root.scope().push(3);
+
+        assert_eq!(root.pop(), Some(2));
+        assert!(!root.contains(&2));
+        assert_eq!(root.pop(), Some(1));
+        assert_eq!(root.pop(), None);
+        assert_structure!(root, [; [3]]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn len_counts_local_and_total_values() {
+        let root = ScopedVec::from(nested!([1, 2; [3; [4, 5]], []]));
+        assert_eq!(root.len(), 2);
+        assert_eq!(root.total_len(), 5);
+
+        let child = root.scope_at_path(&[0]).unwrap();
+        assert_eq!(child.len(), 1);
+        assert_eq!(child.total_len(), 3);
+        assert_eq!(ScopedVec::<i32>::new().total_len(), 0);
+    }
+
+    #[test]
+    fn binary_search_locates_values_by_scope() {
+        let root = ScopedVec::from(nested!([10, 20; [], [30; [40, 50], []], [60]]));
+
+        assert_eq!(root.binary_search(&10), Ok((vec![], 0)));
+        assert_eq!(root.binary_search(&30), Ok((vec![1], 0)));
+        assert_eq!(root.binary_search(&50), Ok((vec![1, 0], 1)));
+        assert_eq!(root.binary_search(&60), Ok((vec![2], 0)));
+        assert_eq!(root.binary_search(&5), Err((vec![], 0)));
+        assert_eq!(root.binary_search(&35), Err((vec![1], 1)));
+        assert_eq!(root.binary_search(&70), Err((vec![2], 1)));
+
+        assert_eq!(root.binary_search_by(|v| (v / 10).cmp(&4)), Ok((vec![1, 0], 0)));
+        assert_eq!(ScopedVec::<i32>::new().binary_search(&1), Err((vec![], 0)));
+    }
+
+    #[test]
+    fn binary_search_doesnt_deadlock_with_appends_or_comparisons() {
+        for _ in 0..50 {
+            let parent = ScopedVec::new();
+            parent.push_all([1, 2]);
+            let child = parent.scope();
+            child.push(3);
+
+            std::thread::scope(|s| {
+                s.spawn(|| (0..50).for_each(|_| drop(parent.binary_search(&2))));
+                s.spawn(|| (0..50).for_each(|i| {
+                    child.append(&parent);
+                    let _ = child == parent;
+                    parent.push(i);
+                }));
+            });
+        }
+    }
+
+    #[test]
+    fn binary_search_insertion_points_keep_the_tree_sorted() {
+        let root = ScopedVec::from(nested!([; [10; [20]], [], [30]]));
+
+        for val in [5, 15, 25, 35, 20] {
+            let (path, index) = match root.binary_search(&val) {
+                Ok(found) | Err(found) => found
+            };
+            root.scope_at_path(&path).unwrap().insert(index, val);
+            root.debug_assert_invariants();
+        }
+
+        assert_eq!(root.to_vec(), [5, 10, 15, 20, 20, 25, 30, 35]);
+    }
+
+    #[test]
+    fn sorted_iter_leaves_scopes_in_place() {
+        let root = ScopedVec::from(nested!([5, 1; [4; [2]], [3, 0]]));
+        let mut sorted = root.sorted_iter();
+        root.push(6);
+
+        assert_eq!(sorted.next(), Some(0));
+        assert_eq!(sorted.collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        assert_structure!(root, [5, 1, 6; [4; [2]], [3, 0]]);
+    }
+
+    #[test]
+    fn to_vec_flattens_in_iteration_order() {
+        let root = ScopedVec::from(nested!([1; [2; [3]], [], [4, 5]]));
+        assert_eq!(root.to_vec(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(root.scope_at_path(&[0]).unwrap().to_vec(), vec![2, 3]);
+        assert_eq!(ScopedVec::<i32>::new().to_vec(), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn count_nonempty_scopes_skips_grouping_scopes() {
+        let root = ScopedVec::from(nested!([; [1; [], [2, 3]], [; [; [4]]], []]));
+        assert_eq!(root.count_nonempty_scopes(), 3);
+        assert_eq!(root.scope_at_path(&[1]).unwrap().count_nonempty_scopes(), 1);
+
+        root.push(0);
+        assert_eq!(root.count_nonempty_scopes(), 4);
+        assert_eq!(ScopedVec::<i32>::new().count_nonempty_scopes(), 0);
+    }
+
+    #[test]
+    fn markers_separate_every_scope() {
+        let root = ScopedVec::from_nested(nested!([1, 2; [; [3]], [4, 5]]));
+
+        let flat = root.to_vec_with_markers(0);
+        assert_eq!(flat, vec![1, 2, 0, 0, 3, 0, 4, 5]);
+
+        let locals: Vec<Vec<i32>> = flat.split(|v| *v == 0).map(<[i32]>::to_vec).collect();
+        let expected: Vec<Vec<i32>> = root.collect_scopes(|_| true)
+            .iter()
+            .map(|scope| scope.iter_pruned(|_| false).copied().collect())
+            .collect();
+        assert_eq!(locals, expected);
+
+        assert_eq!(ScopedVec::new().to_vec_with_markers(0), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn for_each_mut_updates_in_place() {
+        let root = ScopedVec::new_indexed();
+        root.push(1);
+        root.scope().push(2);
+
+        let mut visited = Vec::new();
+        root.for_each_mut(|v| {
+            visited.push(*v);
+            *v *= 10;
+        });
+
+        assert_eq!(visited, vec![1, 2]);
+        assert_eq!(root, [10, 20]);
+        assert!(root.contains(&20));
+        assert!(!root.contains(&2));
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn auto_shrink_releases_capacity_after_removals() {
+        let capacity = |scope: &ScopedVec<i32>| lock::read(&scope.node.inner).values.capacity();
+
+        let root = ScopedVec::new();
+        root.set_auto_shrink(0.25);
+        let child = root.scope();
+        let unshrunk = ScopedVec::new();
+
+        for i in 0..100 {
+            child.push(i);
+            unshrunk.push(i);
+        }
+
+        child.retain_local(|v| *v < 50);
+        unshrunk.retain_local(|v| *v < 10);
+        assert!(capacity(&child) >= 100);
+        assert!(capacity(&unshrunk) >= 100);
+
+        child.retain_local(|v| *v < 10);
+        assert!(capacity(&child) < 100);
+        assert_eq!(child, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn zip_pairs_values_of_same_shaped_trees() {
+        let left = ScopedVec::from_nested(nested!([1; [2, 3], [4]]));
+        let right = left.map(|v| v.to_string());
+
+        assert_eq!(left.zip(&right).unwrap(), vec![
+            (1, "1".to_string()),
+            (2, "2".to_string()),
+            (3, "3".to_string()),
+            (4, "4".to_string()),
+        ]);
+
+        right.scope_at_path(&[0]).unwrap().scope();
+        assert_eq!(left.zip(&right).unwrap_err(), crate::StructureMismatch {
+            path: vec![0],
+            kind: MismatchKind::ChildCount { left: 0, right: 1 }
+        });
+
+        let shorter = ScopedVec::from_nested(nested!([1; [2], [4]]));
+        assert_eq!(left.zip(&shorter).unwrap_err().kind, MismatchKind::LocalLength { left: 2, right: 1 });
+    }
+
+    #[test]
+    fn retain_recent_local_caps_every_scope() {
+        let root = ScopedVec::from_nested(nested!([1, 2, 3, 4; [5], [6, 7, 8; [9, 10]]]));
+
+        root.retain_recent_local(2);
+        assert_structure!(root, [3, 4; [5], [7, 8; [9, 10]]]);
+
+        root.retain_recent_local(0);
+        assert_structure!(root, [; [], [; []]]);
     }
 }
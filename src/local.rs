@@ -0,0 +1,362 @@
+use crate::ttl::Expiry;
+use crate::{Provenance, Storage};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The values stored directly within a single scope, along with any
+/// bookkeeping that has to be kept in sync with them. All mutations of
+/// a scope's values go through here so that bookkeeping can't drift.
+pub(crate) struct Local<T, S = Vec<T>> {
+    pub(crate) values: S,
+    index: Option<Box<dyn ValueIndex<T> + Send + Sync>>,
+    /// The thread which pushed each of `values`, if provenance is being
+    /// tracked for this scope.
+    pub(crate) provenance: Option<Vec<Provenance>>,
+    /// When this scope expires, if it was created using
+    /// [ScopedVec::scope_with_ttl](crate::ScopedVec::scope_with_ttl).
+    pub(crate) expiry: Option<Arc<Expiry>>,
+    /// The expiries of this scope and of its ancestors which have one,
+    /// pushed back by every value pushed into this scope.
+    pub(crate) touches: Vec<Arc<Expiry>>,
+    /// Release the storage's spare capacity once removals leave it
+    /// holding less than this fraction of its capacity, see
+    /// [ScopedVec::set_auto_shrink](crate::ScopedVec::set_auto_shrink).
+    pub(crate) auto_shrink: Option<f32>,
+}
+
+impl<T, S: Default> Default for Local<T, S> {
+    fn default() -> Self {
+        Self {
+            values: S::default(),
+            index: None,
+            provenance: None,
+            expiry: None,
+            touches: Vec::new(),
+            auto_shrink: None
+        }
+    }
+}
+
+impl<T, S: Storage<T>> Local<T, S> {
+    pub(crate) fn indexed() -> Self
+        where T: Eq + Hash + Clone + Send + Sync + 'static
+    {
+        Self {
+            index: Some(Box::new(HashIndex::<T>(HashMap::new()))),
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn with_provenance() -> Self {
+        Self {
+            provenance: Some(Vec::new()),
+            ..Self::default()
+        }
+    }
+
+    /// Create an empty `Local` configured the same way as this one, used
+    /// for the children of this scope.
+    pub(crate) fn new_like(&self) -> Self {
+        Self {
+            index: self.index.as_ref().map(|i| i.new_empty()),
+            provenance: self.provenance.as_ref().map(|_| Vec::new()),
+            auto_shrink: self.auto_shrink,
+            touches: self.touches.clone(),
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn push(&mut self, val: T) {
+        if let Some(index) = &mut self.index {
+            index.insert(&val);
+        }
+        self.values.push(val);
+        if let Some(provenance) = &mut self.provenance {
+            provenance.push(Provenance::current());
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        let val = self.values.pop()?;
+        if let Some(index) = &mut self.index {
+            index.remove(&val);
+        }
+        if let Some(provenance) = &mut self.provenance {
+            provenance.pop();
+        }
+        self.maybe_shrink();
+        Some(val)
+    }
+
+    pub(crate) fn remove(&mut self, i: usize) -> T {
+        let val = self.values.remove(i);
+        if let Some(index) = &mut self.index {
+            index.remove(&val);
+        }
+        if let Some(provenance) = &mut self.provenance {
+            provenance.remove(i);
+        }
+        self.maybe_shrink();
+        val
+    }
+
+    pub(crate) fn swap_remove(&mut self, i: usize) -> T {
+        let val = self.values.swap_remove(i);
+        if let Some(index) = &mut self.index {
+            index.remove(&val);
+        }
+        if let Some(provenance) = &mut self.provenance {
+            provenance.swap_remove(i);
+        }
+        self.maybe_shrink();
+        val
+    }
+
+    pub(crate) fn insert(&mut self, i: usize, val: T) {
+        if let Some(index) = &mut self.index {
+            index.insert(&val);
+        }
+        self.values.insert(i, val);
+        if let Some(provenance) = &mut self.provenance {
+            provenance.insert(i, Provenance::current());
+        }
+    }
+
+    pub(crate) fn swap(&mut self, a: usize, b: usize) {
+        self.values.as_mut_slice().swap(a, b);
+        if let Some(provenance) = &mut self.provenance {
+            provenance.swap(a, b);
+        }
+    }
+
+    /// Retain only the values for which `f` returns true. `f` is run
+    /// against every value before anything is removed, so if it panics
+    /// the scope is left untouched.
+    pub(crate) fn retain<F: FnMut(&T) -> bool>(&mut self, f: F) {
+        let keep: Vec<bool> = self.values.iter().map(f).collect();
+
+        if let Some(index) = &mut self.index {
+            for (val, _) in self.values.iter().zip(&keep).filter(|(_, keep)| !**keep) {
+                index.remove(val);
+            }
+        }
+
+        if let Some(provenance) = &mut self.provenance {
+            let mut keep = keep.iter();
+            provenance.retain(|_| *keep.next().unwrap());
+        }
+
+        let mut keep = keep.into_iter();
+        self.values.retain(|_| keep.next().unwrap());
+        self.maybe_shrink();
+    }
+
+    /// Call `f` on each value in place, rebuilding the index afterwards
+    /// as the values may no longer be where it expects them.
+    pub(crate) fn for_each_mut<F: FnMut(&mut T)>(&mut self, f: F) {
+        self.values.as_mut_slice().iter_mut().for_each(f);
+        if self.index.is_some() {
+            self.repair();
+        }
+    }
+
+    /// Call `f` on the value at `i`, keeping the index in step with it.
+    pub(crate) fn update<F: FnOnce(&mut T)>(&mut self, i: usize, f: F) {
+        if let Some(index) = &mut self.index {
+            index.remove(&self.values.as_slice()[i]);
+        }
+        f(&mut self.values.as_mut_slice()[i]);
+        if let Some(index) = &mut self.index {
+            index.insert(&self.values.as_slice()[i]);
+        }
+    }
+
+    /// Move each value out, replacing it with the result of `f`. Values
+    /// not yet put back when `f` panics are dropped.
+    pub(crate) fn replace_all<F: FnMut(T) -> T>(&mut self, f: F) {
+        let mut values = Vec::with_capacity(self.values.len());
+        self.values.drain_into(&mut values);
+        for val in values.into_iter().map(f) {
+            self.values.push(val);
+        }
+        if self.index.is_some() {
+            self.repair();
+        }
+    }
+
+    /// Shorten this scope to its first `len` values.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        if let Some(index) = &mut self.index {
+            for val in self.values.as_slice().iter().skip(len) {
+                index.remove(val);
+            }
+        }
+        if let Some(provenance) = &mut self.provenance {
+            provenance.truncate(len);
+        }
+        self.values.truncate(len);
+        self.maybe_shrink();
+    }
+
+    /// Move all of the values out of this scope and onto the end of
+    /// `out`.
+    pub(crate) fn take_all_into(&mut self, out: &mut Vec<T>) {
+        if let Some(index) = &mut self.index {
+            index.clear();
+        }
+        if let Some(provenance) = &mut self.provenance {
+            provenance.clear();
+        }
+        self.values.drain_into(out);
+        self.maybe_shrink();
+    }
+
+    /// Move all of `other`'s values onto the end of this scope, keeping
+    /// their provenance if both scopes track it.
+    pub(crate) fn append(&mut self, other: &mut Self) {
+        let provenance = other.provenance.as_mut().map(mem::take);
+        let mut moved = Vec::new();
+        other.take_all_into(&mut moved);
+
+        let start = self.values.len();
+        for val in moved {
+            self.push(val);
+        }
+        if let (Some(mine), Some(theirs)) = (&mut self.provenance, provenance) {
+            mine.truncate(start);
+            mine.extend(theirs);
+        }
+    }
+
+    /// Move the values in `range` out of this scope and onto the end of
+    /// `out`.
+    pub(crate) fn drain_range_into(&mut self, range: Range<usize>, out: &mut Vec<T>) {
+        let start = out.len();
+        self.values.drain_range_into(range.clone(), out);
+        if let Some(index) = &mut self.index {
+            for val in &out[start..] {
+                index.remove(val);
+            }
+        }
+        if let Some(provenance) = &mut self.provenance {
+            provenance.drain(range);
+        }
+        self.maybe_shrink();
+    }
+
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        self.values.reserve(additional);
+        if let Some(provenance) = &mut self.provenance {
+            provenance.reserve(additional);
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.values.shrink_to_fit();
+        if let Some(provenance) = &mut self.provenance {
+            provenance.shrink_to_fit();
+        }
+    }
+
+    /// Apply the auto shrink policy after values have been removed.
+    fn maybe_shrink(&mut self) {
+        if let Some(ratio) = self.auto_shrink {
+            if (self.values.len() as f32) < ratio * self.values.capacity() as f32 {
+                self.shrink_to_fit();
+            }
+        }
+    }
+
+    pub(crate) fn contains(&self, val: &T) -> bool
+        where T: PartialEq
+    {
+        match &self.index {
+            Some(index) => index.contains(val),
+            None => self.values.as_slice().contains(val),
+        }
+    }
+
+    /// Rebuild the bookkeeping for this scope from its values, used to
+    /// get back to a consistent state after a panic part way through a
+    /// mutation.
+    pub(crate) fn repair(&mut self) {
+        if let Some(index) = &mut self.index {
+            index.clear();
+            for val in self.values.iter() {
+                index.insert(val);
+            }
+        }
+        if let Some(provenance) = &mut self.provenance {
+            provenance.resize_with(self.values.len(), Provenance::current);
+        }
+    }
+
+    /// Check the bookkeeping for this scope matches its values.
+    #[cfg(debug_assertions)]
+    pub(crate) fn assert_consistent(&self) {
+        if let Some(index) = &self.index {
+            assert!(index.matches(self.values.as_slice()), "value index is out of sync with the scope's values");
+        }
+        if let Some(provenance) = &self.provenance {
+            assert_eq!(provenance.len(), self.values.len(), "provenance is out of sync with the scope's values");
+        }
+    }
+}
+
+/// An exact index of the values held by a scope. This is type-erased
+/// so that the bounds it requires only need to be satisfied when the
+/// index is created rather than on every method that mutates a scope.
+trait ValueIndex<T> {
+    fn insert(&mut self, val: &T);
+    fn remove(&mut self, val: &T);
+    fn contains(&self, val: &T) -> bool;
+    fn clear(&mut self);
+    fn new_empty(&self) -> Box<dyn ValueIndex<T> + Send + Sync>;
+    #[cfg(debug_assertions)]
+    fn matches(&self, values: &[T]) -> bool;
+}
+
+/// Counts of each value held by a scope.
+struct HashIndex<T>(HashMap<T, usize>);
+
+impl<T: Eq + Hash + Clone + Send + Sync + 'static> ValueIndex<T> for HashIndex<T> {
+    fn insert(&mut self, val: &T) {
+        *self.0.entry(val.clone()).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, val: &T) {
+        if let Some(count) = self.0.get_mut(val) {
+            *count -= 1;
+
+            if *count == 0 {
+                self.0.remove(val);
+            }
+        }
+    }
+
+    fn contains(&self, val: &T) -> bool {
+        self.0.contains_key(val)
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    fn new_empty(&self) -> Box<dyn ValueIndex<T> + Send + Sync> {
+        Box::new(HashIndex::<T>(HashMap::new()))
+    }
+
+    #[cfg(debug_assertions)]
+    fn matches(&self, values: &[T]) -> bool {
+        let mut counts = HashMap::new();
+        for val in values {
+            *counts.entry(val).or_insert(0) += 1;
+        }
+
+        counts.len() == self.0.len()
+            && counts.iter().all(|(val, count)| self.0.get(*val) == Some(count))
+    }
+}
@@ -0,0 +1,316 @@
+//! Acquiring the locks guarding a tree's state, using either `std`'s
+//! locks or, with the `parking_lot` feature, those from `parking_lot`.
+//!
+//! `std`'s locks are poisoned if a thread panics while holding the
+//! write guard. Callbacks which modify a scope's values are run through
+//! `ScopedVec::write_local`, which repairs the scope and releases the
+//! guard before the panic carries on, and no other user code runs under
+//! a write guard. A poisoned lock therefore means one of the tree's own
+//! updates was interrupted part way through, so rather than carrying on
+//! with state that may be inconsistent these panic. The `try_*` methods
+//! on [ScopedVec](crate::ScopedVec) report it as
+//! [ScopedVecError::Poisoned](crate::ScopedVecError::Poisoned) instead.
+//! `parking_lot`'s locks are never poisoned.
+//!
+//! `std`'s locks are unwind safe whatever they hold, as a panic poisons
+//! them rather than leaving broken state silently visible. `parking_lot`'s
+//! aren't, so they're wrapped here to be unwind safe in the same way,
+//! keeping [ScopedVec](crate::ScopedVec) and the other types holding
+//! locks unwind safe whichever backend is used. As above, the tree
+//! repairs any state a panicking callback could have left behind before
+//! its locks are released.
+//!
+//! Neither backend's locks are reentrant, so taking a scope's write lock
+//! on a thread already reading it through an iterator or
+//! [ElementGuard](crate::ElementGuard) never returns. Those mark the
+//! locks they hold as [Held], and in debug builds [write] panics rather
+//! than hanging if it's asked for one of them.
+
+use crate::ScopedVecError;
+#[cfg(debug_assertions)]
+use std::cell::RefCell;
+#[cfg(feature = "parking_lot")]
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::time::Instant;
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(feature = "parking_lot")]
+pub(crate) use parking_lot::{MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(feature = "parking_lot")]
+#[derive(Debug, Default)]
+pub(crate) struct RwLock<T: ?Sized>(parking_lot::RwLock<T>);
+
+#[cfg(feature = "parking_lot")]
+impl<T> RwLock<T> {
+    pub(crate) fn new(val: T) -> Self {
+        Self(parking_lot::RwLock::new(val))
+    }
+}
+
+#[cfg(feature = "parking_lot")]
+impl<T: ?Sized> UnwindSafe for RwLock<T> {}
+#[cfg(feature = "parking_lot")]
+impl<T: ?Sized> RefUnwindSafe for RwLock<T> {}
+
+#[cfg(feature = "parking_lot")]
+#[derive(Debug, Default)]
+pub(crate) struct Mutex<T: ?Sized>(parking_lot::Mutex<T>);
+
+#[cfg(feature = "parking_lot")]
+impl<T> Mutex<T> {
+    pub(crate) fn new(val: T) -> Self {
+        Self(parking_lot::Mutex::new(val))
+    }
+}
+
+#[cfg(feature = "parking_lot")]
+impl<T: ?Sized> UnwindSafe for Mutex<T> {}
+#[cfg(feature = "parking_lot")]
+impl<T: ?Sized> RefUnwindSafe for Mutex<T> {}
+
+#[cfg(not(feature = "parking_lot"))]
+const POISONED: &str = "lock poisoned by a panic part way through an update";
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn read<L>(lock: &RwLock<L>) -> RwLockReadGuard<'_, L> {
+    lock.read().expect(POISONED)
+}
+
+#[cfg(feature = "parking_lot")]
+pub(crate) fn read<L>(lock: &RwLock<L>) -> RwLockReadGuard<'_, L> {
+    lock.0.read()
+}
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn write<L>(lock: &RwLock<L>) -> RwLockWriteGuard<'_, L> {
+    assert_not_held(lock);
+    lock.write().expect(POISONED)
+}
+
+#[cfg(feature = "parking_lot")]
+pub(crate) fn write<L>(lock: &RwLock<L>) -> RwLockWriteGuard<'_, L> {
+    assert_not_held(lock);
+    lock.0.write()
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    /// The addresses of the locks read by this thread's live [Held]s.
+    static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Marks a lock as read locked by the current thread for as long as
+/// this is alive, so [write] can catch the thread deadlocking on it.
+/// Does nothing in release builds.
+pub(crate) struct Held {
+    #[cfg(debug_assertions)]
+    address: usize,
+}
+
+impl Held {
+    pub(crate) fn new<L>(lock: &RwLock<L>) -> Self {
+        #[cfg(debug_assertions)]
+        {
+            let address = address(lock);
+            HELD.with(|held| held.borrow_mut().push(address));
+            Self { address }
+        }
+
+        #[cfg(not(debug_assertions))]
+        {
+            let _ = lock;
+            Self {}
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for Held {
+    fn drop(&mut self) {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(i) = held.iter().rposition(|&address| address == self.address) {
+                held.swap_remove(i);
+            }
+        });
+    }
+}
+
+#[cfg(debug_assertions)]
+fn address<L>(lock: &RwLock<L>) -> usize {
+    lock as *const RwLock<L> as usize
+}
+
+#[cfg(debug_assertions)]
+fn assert_not_held<L>(lock: &RwLock<L>) {
+    let address = address(lock);
+    assert!(
+        !HELD.with(|held| held.borrow().contains(&address)),
+        "a scope was modified while this thread was iterating over it or \
+         holding one of its values, which would deadlock"
+    );
+}
+
+#[cfg(not(debug_assertions))]
+fn assert_not_held<L>(_: &RwLock<L>) {}
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn lock<L>(lock: &Mutex<L>) -> MutexGuard<'_, L> {
+    lock.lock().expect(POISONED)
+}
+
+#[cfg(feature = "parking_lot")]
+pub(crate) fn lock<L>(lock: &Mutex<L>) -> MutexGuard<'_, L> {
+    lock.0.lock()
+}
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn into_inner<L>(lock: RwLock<L>) -> L {
+    lock.into_inner().expect(POISONED)
+}
+
+#[cfg(feature = "parking_lot")]
+pub(crate) fn into_inner<L>(lock: RwLock<L>) -> L {
+    lock.0.into_inner()
+}
+
+/// Take a read lock without blocking.
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn try_read<L>(lock: &RwLock<L>) -> Result<RwLockReadGuard<'_, L>, ScopedVecError> {
+    lock.try_read().map_err(from_try_lock)
+}
+
+/// Take a read lock without blocking.
+#[cfg(feature = "parking_lot")]
+pub(crate) fn try_read<L>(lock: &RwLock<L>) -> Result<RwLockReadGuard<'_, L>, ScopedVecError> {
+    lock.0.try_read().ok_or(ScopedVecError::WouldBlock)
+}
+
+/// Take a write lock without blocking.
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn try_write<L>(lock: &RwLock<L>) -> Result<RwLockWriteGuard<'_, L>, ScopedVecError> {
+    lock.try_write().map_err(from_try_lock)
+}
+
+/// Take a write lock without blocking.
+#[cfg(feature = "parking_lot")]
+pub(crate) fn try_write<L>(lock: &RwLock<L>) -> Result<RwLockWriteGuard<'_, L>, ScopedVecError> {
+    lock.0.try_write().ok_or(ScopedVecError::WouldBlock)
+}
+
+/// Take a read lock, giving up once `deadline` has passed. `std`'s
+/// locks can't be waited on with a timeout, so this polls the lock,
+/// backing off up to a millisecond between attempts.
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn read_until<L>(lock: &RwLock<L>, deadline: Instant) -> Result<RwLockReadGuard<'_, L>, ScopedVecError> {
+    use std::time::Duration;
+
+    let mut backoff = Duration::from_micros(10);
+
+    loop {
+        match try_read(lock) {
+            Err(ScopedVecError::WouldBlock) => {}
+            result => return result,
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(ScopedVecError::Timeout);
+        }
+
+        std::thread::sleep(backoff.min(deadline - now));
+        backoff = (backoff * 2).min(Duration::from_millis(1));
+    }
+}
+
+/// Take a read lock, giving up once `deadline` has passed.
+#[cfg(feature = "parking_lot")]
+pub(crate) fn read_until<L>(lock: &RwLock<L>, deadline: Instant) -> Result<RwLockReadGuard<'_, L>, ScopedVecError> {
+    lock.0.try_read_for(deadline.saturating_duration_since(Instant::now()))
+        .ok_or(ScopedVecError::Timeout)
+}
+
+#[cfg(not(feature = "parking_lot"))]
+fn from_try_lock<G>(e: std::sync::TryLockError<G>) -> ScopedVecError {
+    match e {
+        std::sync::TryLockError::Poisoned(_) => ScopedVecError::Poisoned,
+        std::sync::TryLockError::WouldBlock => ScopedVecError::WouldBlock,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ScopedVec, ScopedVecError};
+    use std::panic::{self, AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
+    use std::time::{Duration, Instant};
+
+    fn assert_unwind_safe<T: UnwindSafe + RefUnwindSafe>() {}
+
+    #[test]
+    fn trees_are_unwind_safe() {
+        assert_unwind_safe::<ScopedVec<i32>>();
+        assert_unwind_safe::<crate::ScopedVecForest<i32>>();
+        assert_unwind_safe::<crate::InternedScopedVec>();
+        assert_unwind_safe::<crate::ThreadScoped<i32>>();
+    }
+
+    #[test]
+    fn try_helpers_report_held_locks() {
+        let lock = super::RwLock::new(1);
+        let guard = super::write(&lock);
+        assert!(matches!(super::try_read(&lock), Err(ScopedVecError::WouldBlock)));
+        assert!(matches!(super::try_write(&lock), Err(ScopedVecError::WouldBlock)));
+
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert!(matches!(super::read_until(&lock, deadline), Err(ScopedVecError::Timeout)));
+        assert!(Instant::now() >= deadline);
+
+        drop(guard);
+        assert_eq!(*super::read_until(&lock, deadline).unwrap(), 1);
+        *super::try_write(&lock).unwrap() += 1;
+        assert_eq!(super::into_inner(lock), 2);
+    }
+
+    #[test]
+    fn locks_stay_usable_after_a_callback_panics() {
+        let root = ScopedVec::new();
+        root.push(1);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| root.retain_local(|_| panic!("boom"))));
+        assert!(result.is_err());
+        assert!(root.try_push(2).is_ok());
+        assert_eq!(root, [1, 2]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "would deadlock")]
+    fn modifying_a_scope_while_iterating_it_panics() {
+        let root = ScopedVec::new();
+        root.push(1);
+
+        for v in root.iter() {
+            root.push(*v);
+        }
+    }
+
+    #[test]
+    fn scopes_outside_an_iterator_stay_writable() {
+        let root = ScopedVec::new();
+        let left = root.scope();
+        let right = root.scope();
+        left.push(1);
+
+        for v in left.iter() {
+            right.push(*v);
+        }
+        let first = left.first().unwrap();
+        right.push(*first + 1);
+        drop(first);
+        left.push(3);
+
+        assert_eq!(root, [1, 3, 1, 2]);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{lock, ScopedVec};
+use crate::lock::RwLock;
+use std::cell::RefCell;
+
+/// An owned copy of a [log::Record], as captured by [ScopedLogger].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub level: log::Level,
+    pub target: String,
+    pub message: String,
+}
+
+thread_local! {
+    static THREAD_SCOPE: RefCell<Option<ScopedVec<LogRecord>>> = const { RefCell::new(None) };
+}
+
+/// A [log::Log] implementation which captures records into a
+/// `ScopedVec`.
+///
+/// Records are pushed into the scope the current thread has entered
+/// using [ScopedLogger::enter], or the logger's target scope if the
+/// thread hasn't entered one. Since `log` only allows a single logger
+/// to be installed per process, the target can be swapped out at
+/// runtime using [ScopedLogger::set_target].
+pub struct ScopedLogger {
+    target: RwLock<ScopedVec<LogRecord>>,
+}
+
+impl ScopedLogger {
+    /// Install a new `ScopedLogger` as the global logger, capturing
+    /// records of every level into `target`. Fails if a logger has
+    /// already been installed.
+    pub fn install(target: ScopedVec<LogRecord>) -> Result<&'static ScopedLogger, log::SetLoggerError> {
+        let logger = Box::into_raw(Box::new(ScopedLogger {
+            target: RwLock::new(target)
+        }));
+
+        // the logger is only leaked once it's been installed, as `log`
+        // doesn't hold on to it otherwise
+        if let Err(e) = log::set_logger(unsafe { &*logger }) {
+            drop(unsafe { Box::from_raw(logger) });
+            return Err(e);
+        }
+        log::set_max_level(log::LevelFilter::Trace);
+
+        Ok(unsafe { &*logger })
+    }
+
+    /// Swap out the scope records are pushed into for threads that
+    /// haven't entered a scope of their own.
+    pub fn set_target(&self, target: ScopedVec<LogRecord>) {
+        *lock::write(&self.target) = target;
+    }
+
+    /// Route records logged by the current thread into `scope` until the
+    /// returned guard is dropped, at which point the previously entered
+    /// scope (if any) is restored.
+    pub fn enter(scope: ScopedVec<LogRecord>) -> EnteredScope {
+        let previous = THREAD_SCOPE.with(|s| s.borrow_mut().replace(scope));
+        EnteredScope { previous }
+    }
+}
+
+impl log::Log for ScopedLogger {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        let record = LogRecord {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string()
+        };
+
+        let scope = THREAD_SCOPE.with(|s| s.borrow().clone())
+            .unwrap_or_else(|| lock::read(&self.target).clone());
+        scope.push(record);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Guard returned by [ScopedLogger::enter], restoring the thread's
+/// previously entered scope when dropped.
+pub struct EnteredScope {
+    previous: Option<ScopedVec<LogRecord>>,
+}
+
+impl Drop for EnteredScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        THREAD_SCOPE.with(|s| *s.borrow_mut() = previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogRecord, ScopedLogger};
+    use crate::ScopedVec;
+
+    #[test]
+    fn records_are_captured_into_entered_scope() {
+        let root = ScopedVec::new();
+        ScopedLogger::install(root.clone()).unwrap();
+
+        let unused = ScopedVec::new();
+        assert!(ScopedLogger::install(unused.clone()).is_err());
+        assert_eq!(unused.strong_count(), 1);
+
+        let scope1 = root.scope();
+        let scope2 = root.scope();
+
+        {
+            let _entered = ScopedLogger::enter(scope1.clone());
+            log::info!("hello from {}", 1);
+        }
+        log::warn!("hello from root");
+
+        let mut iter = scope1.iter();
+        assert_eq!(iter.next(), Some(&LogRecord {
+            level: log::Level::Info,
+            target: module_path!().to_string(),
+            message: "hello from 1".to_string()
+        }));
+        assert_eq!(iter.next(), None);
+
+        assert!(scope2.is_empty());
+        assert!(scope2.is_empty_deep());
+
+        let mut iter = root.iter().map(|r| r.message.as_str());
+        assert_eq!(iter.next(), Some("hello from root"));
+        assert_eq!(iter.next(), Some("hello from 1"));
+        assert_eq!(iter.next(), None);
+    }
+}
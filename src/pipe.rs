@@ -0,0 +1,345 @@
+use crate::{lock, Node, ScopedVec, Storage};
+use crate::lock::{Mutex, RwLock};
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+/// Called with the scope a value is being pushed to and the value, while
+/// the scope's write lock is held. Returns false once it no longer wants
+/// to be called.
+pub(crate) type Observer<T, S> = Arc<dyn Fn(&ScopedVec<T, S>, &T) -> bool + Send + Sync>;
+
+/// The number of observers registered across every tree, so pushes can
+/// skip looking for observers when there aren't any.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// The observers registered on a scope. Any still registered when the
+/// scope is dropped stop counting towards [ACTIVE] then, so the fast
+/// path comes back once every observed scope is gone.
+pub(crate) struct Observers<T, S>(RwLock<Vec<Observer<T, S>>>);
+
+impl<T, S> Default for Observers<T, S> {
+    fn default() -> Self {
+        Self(RwLock::default())
+    }
+}
+
+impl<T, S> Deref for Observers<T, S> {
+    type Target = RwLock<Vec<Observer<T, S>>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T, S> Drop for Observers<T, S> {
+    fn drop(&mut self) {
+        let registered = lock::read(&self.0).len();
+        ACTIVE.fetch_sub(registered, Ordering::SeqCst);
+    }
+}
+
+/// Stops forwarding values to the channel given to [ScopedVec::pipe_to]
+/// when dropped.
+pub struct PipeHandle {
+    unregister: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl PipeHandle {
+    /// Stop forwarding values, the same as dropping the handle.
+    pub fn stop(mut self) {
+        self.unregister();
+    }
+
+    fn unregister(&mut self) {
+        if let Some(unregister) = self.unregister.take() {
+            unregister();
+        }
+    }
+}
+
+impl Drop for PipeHandle {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// The state shared between a pipe's backfill and its observer.
+struct Pipe<T> {
+    tx: Sender<T>,
+    /// The address of the scope being piped.
+    root: usize,
+    /// Set once every scope which existed when the pipe was created has
+    /// been backfilled, after which every push is forwarded.
+    backfilled: bool,
+    /// The scopes whose values have been sent by the backfill.
+    done: HashSet<usize>,
+    /// The scopes the backfill has found and will go on to send.
+    found: HashSet<usize>,
+}
+
+impl<T> Pipe<T> {
+    /// Whether a value pushed to `scope` now should be forwarded, rather
+    /// than being left for the backfill to pick up.
+    ///
+    /// A scope is backfilled if it's the root, or it was among its
+    /// parent's children when the parent was backfilled. Scopes created
+    /// under a parent after that point are never reached by the
+    /// backfill, so pushes to them have to be forwarded straight away.
+    fn forwards<S>(&self, scope: &ScopedVec<T, S>) -> bool {
+        if self.backfilled || self.done.contains(&addr(&scope.node)) {
+            return true;
+        }
+
+        let mut current = scope.node.clone();
+        loop {
+            let current_addr = addr(&current);
+            if current_addr == self.root || self.found.contains(&current_addr) {
+                return false;
+            }
+
+            let parent = match lock::read(&current.parent).upgrade() {
+                Some(parent) => parent,
+                None => return true,
+            };
+            if self.done.contains(&addr(&parent)) {
+                return true;
+            }
+            current = parent;
+        }
+    }
+}
+
+fn addr<T, S>(node: &Arc<Node<T, S>>) -> usize {
+    Arc::as_ptr(node) as *const () as usize
+}
+
+impl<T, S: Storage<T>> ScopedVec<T, S> {
+    /// Send a clone of every value in this scope and its descendants to
+    /// `tx` in the order given by [ScopedVec::iter], then carry on
+    /// sending every value added using [ScopedVec::push],
+    /// [ScopedVec::try_push], [ScopedVec::push_all] or
+    /// [ScopedVec::insert] to this scope or any of its descendants,
+    /// including those created later, until the returned handle is
+    /// dropped or the receiver hangs up.
+    ///
+    /// Each value is delivered exactly once. A scope's existing values are
+    /// sent while holding its read lock, and a push is only forwarded
+    /// once the scope it's pushed to has been sent, so a value pushed
+    /// while the existing values are still being sent is either picked
+    /// up with them or forwarded afterwards. Values pushed to scopes that
+    /// have already been sent can arrive before the existing values of
+    /// those that haven't.
+    pub fn pipe_to(&self, tx: Sender<T>) -> PipeHandle
+        where T: Clone + Send + Sync + 'static, S: Send + Sync + 'static
+    {
+        let pipe = Arc::new(Mutex::new(Pipe {
+            tx,
+            root: addr(&self.node),
+            backfilled: false,
+            done: HashSet::new(),
+            found: HashSet::new()
+        }));
+
+        let observer: Observer<T, S> = {
+            let pipe = pipe.clone();
+            Arc::new(move |scope, val| {
+                let pipe = lock::lock(&pipe);
+                !pipe.forwards(scope) || pipe.tx.send(val.clone()).is_ok()
+            })
+        };
+        self.observe(observer.clone());
+
+        let node = Arc::downgrade(&self.node);
+        let mut handle = PipeHandle {
+            unregister: Some(Box::new(move || {
+                if let Some(node) = node.upgrade() {
+                    unobserve(&node, &observer);
+                }
+            }))
+        };
+
+        let mut stack = vec![self.clone()];
+        while let Some(scope) = stack.pop() {
+            let local = lock::read(&scope.node.inner);
+            let children = lock::read(&scope.node.children);
+
+            let mut pipe = lock::lock(&pipe);
+            pipe.done.insert(addr(&scope.node));
+            pipe.found.extend(children.iter().map(|child| addr(&child.node)));
+
+            if local.values.iter().any(|val| pipe.tx.send(val.clone()).is_err()) {
+                drop(pipe);
+                drop(local);
+                drop(children);
+                handle.unregister();
+                return handle;
+            }
+
+            stack.extend(children.iter().rev().cloned());
+        }
+
+        let mut pipe = lock::lock(&pipe);
+        pipe.backfilled = true;
+        pipe.done = HashSet::new();
+        pipe.found = HashSet::new();
+        drop(pipe);
+
+        handle
+    }
+
+    pub(crate) fn observe(&self, observer: Observer<T, S>) {
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        lock::write(&self.node.observers).push(observer);
+    }
+
+    /// Let the observers registered on this scope and its ancestors know
+    /// `val` is being pushed to this scope. Must be called while holding
+    /// this scope's write lock.
+    pub(crate) fn notify_push(&self, val: &T) {
+        if ACTIVE.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        let mut node = Some(self.node.clone());
+        while let Some(current) = node {
+            let observers = lock::read(&current.observers).clone();
+            for observer in observers {
+                if !observer(self, val) {
+                    unobserve(&current, &observer);
+                }
+            }
+
+            node = lock::read(&current.parent).upgrade();
+        }
+    }
+}
+
+/// Serializes the tests which register observers, so they can tell how
+/// many of them are [ACTIVE].
+#[cfg(test)]
+pub(crate) fn serialize_observers() -> std::sync::MutexGuard<'static, ()> {
+    static SERIAL: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    SERIAL.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+fn unobserve<T, S>(node: &Node<T, S>, observer: &Observer<T, S>) {
+    let mut observers = lock::write(&node.observers);
+    if let Some(i) = observers.iter().position(|o| Arc::ptr_eq(o, observer)) {
+        observers.remove(i);
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serialize_observers, ACTIVE};
+    use crate::ScopedVec;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+
+    #[test]
+    fn backfills_then_forwards() {
+        let _serial = serialize_observers();
+        let root = ScopedVec::from_nested(crate::nested!([1; [2; [3]], [4]]));
+        let (tx, rx) = mpsc::channel();
+
+        let handle = root.pipe_to(tx);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+
+        let late = root.scope_at_path(&[0]).unwrap().scope();
+        late.push(5);
+        late.scope().push(6);
+        root.push(7);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![5, 6, 7]);
+
+        handle.stop();
+        late.push(8);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn pipes_only_see_their_own_subtree() {
+        let _serial = serialize_observers();
+        let root = ScopedVec::new();
+        let piped = root.scope();
+        let (tx, rx) = mpsc::channel();
+
+        let _handle = piped.pipe_to(tx);
+        root.push(1);
+        root.scope().push(2);
+        piped.push(3);
+
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn unregisters_when_dropped_or_disconnected() {
+        let _serial = serialize_observers();
+        let root = ScopedVec::new();
+        let (tx, rx) = mpsc::channel();
+
+        drop(root.pipe_to(tx.clone()));
+        root.push(1);
+        assert!(rx.try_recv().is_err());
+
+        let _handle = root.pipe_to(tx);
+        drop(rx);
+        root.push(2);
+        assert!(crate::lock::read(&root.node.observers).is_empty());
+    }
+
+    #[test]
+    fn concurrent_pushes_are_delivered_exactly_once() {
+        let _serial = serialize_observers();
+        let root = ScopedVec::new();
+        let scopes = [root.clone(), root.scope(), root.scope().scope()];
+        for i in 0..1000 {
+            scopes[i % scopes.len()].push(i);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let total = 20_000;
+
+        std::thread::scope(|s| {
+            let pusher = s.spawn(|| {
+                for i in 1000..total {
+                    let scope = &scopes[i % scopes.len()];
+                    if i % 97 == 0 {
+                        scope.scope().push(i);
+                    } else {
+                        scope.push(i);
+                    }
+                }
+            });
+
+            let _handle = root.pipe_to(tx);
+            pusher.join().unwrap();
+
+            let mut received: Vec<_> = rx.try_iter().collect();
+            received.sort_unstable();
+            assert_eq!(received, (0..total).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn dropping_observed_scopes_restores_the_fast_path() {
+        let _serial = serialize_observers();
+        let before = ACTIVE.load(Ordering::SeqCst);
+
+        let root = ScopedVec::<i32>::new();
+        let piped = root.scope();
+        let (tx, _rx) = mpsc::channel();
+        let handle = piped.pipe_to(tx);
+        assert_eq!(ACTIVE.load(Ordering::SeqCst), before + 1);
+
+        drop(root);
+        drop(piped);
+        assert_eq!(ACTIVE.load(Ordering::SeqCst), before);
+
+        drop(handle);
+        assert_eq!(ACTIVE.load(Ordering::SeqCst), before);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{lock, ScopedVec, Storage};
+use std::mem;
+use std::sync::atomic;
+use std::sync::Arc;
+
+/// Called when the last counted handle to a scope is dropped, see
+/// [ScopedVec::set_promote_on_drop].
+pub(crate) type Promote<T, S> = fn(&ScopedVec<T, S>);
+
+impl<T, S: Storage<T>> ScopedVec<T, S> {
+    /// When enabled, dropping the last handle to this scope given out by
+    /// the method which created it, or cloned from one, removes the scope
+    /// from its parent and moves its values up into the parent, after
+    /// the parent's own values. The scope's children take its place among
+    /// the parent's children. The values are pushed into the parent as
+    /// with [ScopedVec::push], so pipes on the parent or its ancestors are
+    /// sent them again and watchers of the parent are notified.
+    ///
+    /// Handles the tree holds on to itself, or hands out when looking
+    /// scopes up, such as those from [ScopedVec::scope_at_path], don't
+    /// count. Dropping them never promotes the scope, so neither do
+    /// methods which walk the tree. If the parent or this scope is locked
+    /// when the last handle is dropped, say because either is being
+    /// iterated, the scope is left where it is as though this were
+    /// disabled, rather than risking a deadlock. If the parent has already
+    /// been dropped, or the scope has been detached from it, there's
+    /// nowhere for the values to go and they're dropped along with the
+    /// scope as usual.
+    pub fn set_promote_on_drop(&self, enabled: bool) {
+        self.node.promote_on_drop.store(enabled, atomic::Ordering::Release);
+    }
+
+    /// Move this scope's values and children up into its parent.
+    pub(crate) fn promote(&self) {
+        let parent = match lock::read(&self.node.parent).upgrade() {
+            Some(node) => ScopedVec { node, counted: false },
+            None => return,
+        };
+
+        // the thread dropping the last handle could be holding any of these,
+        // say while iterating over the parent or this scope, so they're
+        // only tried
+        let locks = (
+            lock::try_write(&parent.node.children),
+            lock::try_write(&self.node.children),
+            lock::try_write(&self.node.inner),
+            lock::try_write(&parent.node.inner)
+        );
+        let (mut siblings, mut children, local, parent_local) = match locks {
+            (Ok(siblings), Ok(children), Ok(local), Ok(parent_local)) => (siblings, children, local, parent_local),
+            _ => return,
+        };
+
+        let i = match siblings.iter().position(|child| child.ptr_eq(self)) {
+            Some(i) => i,
+            None => return,
+        };
+
+        let children = mem::take(&mut *children);
+        for child in &children {
+            *lock::write(&child.node.parent) = Arc::downgrade(&parent.node);
+        }
+
+        let removed = siblings.remove(i);
+        siblings.splice(i..i, children);
+        drop(siblings);
+        self.detach();
+
+        let mut values = Vec::new();
+        Self::update_local(local, |local| local.take_all_into(&mut values));
+        self.len_changed();
+
+        Self::update_local(parent_local, |local| {
+            for val in values {
+                local.touch();
+                parent.notify_push(&val);
+                local.push(val);
+            }
+        });
+        parent.len_changed();
+
+        drop(removed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assert_structure, ScopedVec};
+    use std::sync::mpsc;
+
+    #[test]
+    fn moves_values_and_children_into_the_parent() {
+        let root = ScopedVec::new();
+        root.push(1);
+        let scope = root.scope();
+        scope.set_promote_on_drop(true);
+        scope.push(2);
+        scope.scope().push(3);
+        root.scope().push(4);
+
+        let other = scope.clone();
+        drop(scope);
+        assert_structure!(root, [1; [2; [3]], [4]]);
+
+        drop(other);
+        assert_structure!(root, [1, 2; [3], [4]]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn stays_put_while_the_parent_is_locked() {
+        let root = ScopedVec::new();
+        let scope = root.scope();
+        scope.set_promote_on_drop(true);
+        scope.push(1);
+
+        let mut iter = root.iter();
+        drop(scope);
+        assert_eq!(iter.next(), Some(&1));
+        drop(iter);
+
+        assert_structure!(root, [; [1]]);
+        assert_eq!(root.total_len(), 1);
+        assert_structure!(root, [; [1]]);
+    }
+
+    #[test]
+    fn stays_put_while_its_children_are_iterated() {
+        let root = ScopedVec::new();
+        let scope = root.scope();
+        scope.set_promote_on_drop(true);
+        scope.push(1);
+        scope.scope().push(2);
+
+        let lookup = root.scope_at_path(&[0]).unwrap();
+        let mut iter = lookup.iter();
+        drop(scope);
+        assert_eq!(iter.next(), Some(&1));
+        assert_eq!(iter.next(), Some(&2));
+        drop(iter);
+
+        assert_structure!(root, [; [1; [2]]]);
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn promoted_values_are_pushed_to_the_parent() {
+        let _serial = crate::pipe::serialize_observers();
+        let root = ScopedVec::new();
+        let scope = root.scope();
+        scope.set_promote_on_drop(true);
+        scope.push(1);
+
+        let (tx, rx) = mpsc::channel();
+        let _pipe = root.pipe_to(tx);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1]);
+
+        drop(scope);
+        assert_structure!(root, [1]);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn walking_the_tree_doesnt_promote() {
+        let root = ScopedVec::new();
+        let scope = root.scope();
+        scope.set_promote_on_drop(true);
+        scope.push(1);
+
+        assert_eq!(root.total_len(), 1);
+        root.for_each_scope(|_| true, |_| {});
+        drop(root.scope_at_path(&[0]).unwrap());
+        assert_structure!(root, [; [1]]);
+
+        let lookup = root.scope_at_path(&[0]).unwrap();
+        drop(scope);
+        assert_structure!(root, [1]);
+        assert!(lookup.is_empty());
+    }
+
+    #[test]
+    fn discards_values_without_a_parent() {
+        let root = ScopedVec::new();
+        let scope = root.scope();
+        scope.set_promote_on_drop(true);
+        scope.push(1);
+
+        drop(root);
+        drop(scope);
+
+        let root = ScopedVec::new();
+        root.push(1);
+        root.set_promote_on_drop(true);
+        drop(root);
+    }
+}
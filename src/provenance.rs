@@ -0,0 +1,109 @@
+use crate::{ScopedVec, ScopedVecGuardHolder, Storage};
+use owning_ref::OwningHandle;
+use std::thread::{self, ThreadId};
+
+/// The thread which pushed a value into a tree created using
+/// [ScopedVec::new_with_provenance].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Provenance {
+    pub thread_id: ThreadId,
+    pub thread_name: Option<String>,
+}
+
+impl Provenance {
+    pub(crate) fn current() -> Self {
+        let thread = thread::current();
+
+        Self {
+            thread_id: thread.id(),
+            thread_name: thread.name().map(str::to_string)
+        }
+    }
+}
+
+type BoxedIter<'a, T> = Box<dyn Iterator<Item = (Provenance, &'a T)> + 'a>;
+
+/// Iterator returned by [ScopedVec::iter_with_provenance].
+pub struct ProvenanceIterator<'a, T, S = Vec<T>> {
+    iterator: OwningHandle<Box<ScopedVecGuardHolder<'a, T, S>>, BoxedIter<'a, T>>,
+}
+
+impl<'a, T, S: Storage<T>> ProvenanceIterator<'a, T, S> {
+    pub(crate) fn new(vec: &'a ScopedVec<T, S>) -> Self {
+        Self {
+            iterator: OwningHandle::new_with_fn(
+                Box::new(ScopedVecGuardHolder::read(vec)),
+                |g| {
+                    // the value behind the raw pointer `g` is boxed, so we're safe to dereference
+                    let guards = unsafe { &*g };
+
+                    let local = guards.inner.provenance.iter()
+                        .flatten()
+                        .cloned()
+                        .zip(guards.inner.values.iter());
+
+                    Box::new(local.chain(guards.children.iter()
+                        .flat_map(ProvenanceIterator::new))) as BoxedIter<'a, T>
+                }
+            )
+        }
+    }
+}
+
+impl<'a, T, S: Storage<T>> Iterator for ProvenanceIterator<'a, T, S> {
+    type Item = (Provenance, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iterator.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ScopedVec;
+
+    #[test]
+    fn records_the_pushing_thread() {
+        let root = ScopedVec::new_with_provenance();
+        root.push(0);
+        let scope = root.scope();
+
+        let handles: Vec<_> = ["worker-a", "worker-b"].iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let scope = scope.clone();
+                std::thread::Builder::new()
+                    .name(name.to_string())
+                    .spawn(move || {
+                        for v in 0..50 {
+                            scope.push((i + 1) * 100 + v);
+                        }
+                    })
+                    .unwrap()
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let values: Vec<_> = root.iter_with_provenance().collect();
+        assert_eq!(values.len(), 101);
+        assert_eq!(values[0].0.thread_id, std::thread::current().id());
+
+        for (provenance, v) in &values[1..] {
+            let expected = if **v < 200 { "worker-a" } else { "worker-b" };
+            assert_eq!(provenance.thread_name.as_deref(), Some(expected));
+        }
+
+        assert!(format!("{:?}", scope).contains("worker-b"));
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn untracked_trees_yield_nothing() {
+        let root = ScopedVec::new();
+        root.push(3);
+        assert_eq!(root.iter_with_provenance().count(), 0);
+        assert!(!format!("{:?}", root).contains("provenance"));
+    }
+}
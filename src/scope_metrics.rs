@@ -0,0 +1,111 @@
+use crate::{lock, ScopedVec, Storage};
+
+/// Integration with the [metrics] facade, exporting the size and shape
+/// of a tree as gauges.
+impl<T, S: Storage<T>> ScopedVec<T, S> {
+    /// Describe the gauges exported for this tree under the given prefix
+    /// and report their current values. After registering, the gauges
+    /// can be brought up to date by calling [ScopedVec::report_metrics],
+    /// usually on a poll interval.
+    ///
+    /// The following gauges are exported:
+    ///
+    /// - `{prefix}_values` - the number of values held by the tree.
+    /// - `{prefix}_scopes` - the number of scopes in the tree, including
+    ///   this one.
+    /// - `{prefix}_max_depth` - the depth of the deepest scope in the
+    ///   tree, with this scope being at depth 0.
+    /// - `{prefix}_scope_values` - the number of values held directly by
+    ///   each scope, labelled with the `path` of child indices to that
+    ///   scope from this one, such as `/0/2`.
+    pub fn register_metrics(&self, prefix: &str) {
+        metrics::describe_gauge!(format!("{}_values", prefix), "Number of values held by the tree");
+        metrics::describe_gauge!(format!("{}_scopes", prefix), "Number of scopes in the tree");
+        metrics::describe_gauge!(format!("{}_max_depth", prefix), "Depth of the deepest scope in the tree");
+        metrics::describe_gauge!(format!("{}_scope_values", prefix), "Number of values held directly by a scope");
+
+        *lock::write(&self.node.metrics_prefix) = Some(prefix.into());
+        self.report_metrics();
+    }
+
+    /// Update the gauges registered using [ScopedVec::register_metrics]
+    /// with the current state of the tree. Does nothing if metrics
+    /// haven't been registered for this scope.
+    pub fn report_metrics(&self) {
+        let prefix = match lock::read(&self.node.metrics_prefix).clone() {
+            Some(prefix) => prefix,
+            None => return,
+        };
+
+        let mut values = 0;
+        let mut scopes = 0;
+        let mut max_depth = 0;
+
+        self.walk_with_paths(|path, scope| {
+            let len = lock::read(&scope.node.inner).values.len();
+
+            values += len;
+            scopes += 1;
+            max_depth = max_depth.max(path.len());
+
+            let path: String = path.iter().map(|i| format!("/{}", i)).collect();
+            let path = if path.is_empty() { "/".to_string() } else { path };
+            metrics::gauge!(format!("{}_scope_values", prefix), "path" => path).set(len as f64);
+        });
+
+        metrics::gauge!(format!("{}_values", prefix)).set(values as f64);
+        metrics::gauge!(format!("{}_scopes", prefix)).set(scopes as f64);
+        metrics::gauge!(format!("{}_max_depth", prefix)).set(max_depth as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ScopedVec;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use std::collections::HashMap;
+
+    #[test]
+    fn gauges_reflect_tree() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        metrics::with_local_recorder(&recorder, || {
+            let root = ScopedVec::new();
+            root.push(1);
+            root.register_metrics("tree");
+
+            let scoped = root.scope();
+            scoped.push(2);
+            scoped.push(3);
+            scoped.scope().push(4);
+            root.scope();
+
+            root.report_metrics();
+        });
+
+        let gauges: HashMap<_, _> = snapshotter.snapshot()
+            .into_vec()
+            .into_iter()
+            .filter_map(|(key, _, _, value)| {
+                let key = key.key();
+                let path = key.labels().find(|l| l.key() == "path").map(|l| l.value().to_string());
+
+                match value {
+                    DebugValue::Gauge(v) => Some(((key.name().to_string(), path), v.into_inner())),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let gauge = |name: &str, path: Option<&str>| gauges[&(name.to_string(), path.map(str::to_string))];
+
+        assert_eq!(gauge("tree_values", None), 4.0);
+        assert_eq!(gauge("tree_scopes", None), 4.0);
+        assert_eq!(gauge("tree_max_depth", None), 2.0);
+        assert_eq!(gauge("tree_scope_values", Some("/")), 1.0);
+        assert_eq!(gauge("tree_scope_values", Some("/0")), 2.0);
+        assert_eq!(gauge("tree_scope_values", Some("/0/0")), 1.0);
+        assert_eq!(gauge("tree_scope_values", Some("/1")), 0.0);
+    }
+}
@@ -0,0 +1,262 @@
+use std::ops::Range;
+use std::slice;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The backing storage for the values held directly by a single scope,
+/// allowing the `Vec` used by default to be swapped out using the `S`
+/// parameter of [ScopedVec](crate::ScopedVec).
+///
+/// This trait is sealed, implementations are provided for `Vec` and,
+/// with the `smallvec` feature enabled, for `SmallVec`.
+pub trait Storage<T>: Default + sealed::Sealed {
+    fn push(&mut self, val: T);
+
+    fn pop(&mut self) -> Option<T>;
+
+    /// Insert `val` at `index`, shifting the values after it up. Panics if
+    /// `index` is greater than the length.
+    fn insert(&mut self, index: usize, val: T);
+
+    /// Remove the value at `index`, shifting those after it down. Panics
+    /// if `index` is out of bounds.
+    fn remove(&mut self, index: usize) -> T;
+
+    /// Remove the value at `index`, replacing it with the last value.
+    /// Panics if `index` is out of bounds.
+    fn swap_remove(&mut self, index: usize) -> T;
+
+    /// Shorten the storage to `len` values, dropping the rest. Does
+    /// nothing if the storage is already shorter than `len`.
+    fn truncate(&mut self, len: usize);
+
+    fn as_slice(&self) -> &[T];
+
+    fn as_mut_slice(&mut self) -> &mut [T];
+
+    /// Retain only the values for which `f` returns true, preserving
+    /// their order. `f` is called exactly once for each value, in order.
+    fn retain<F: FnMut(&T) -> bool>(&mut self, f: F);
+
+    /// Move all of the values out of the storage and onto the end of
+    /// `out`.
+    fn drain_into(&mut self, out: &mut Vec<T>);
+
+    /// Move the values in `range` out of the storage and onto the end of
+    /// `out`, shifting those after them down.
+    fn drain_range_into(&mut self, range: Range<usize>, out: &mut Vec<T>);
+
+    /// The number of values the storage can hold without allocating.
+    fn capacity(&self) -> usize;
+
+    /// Reserve room for at least `additional` more values.
+    fn reserve(&mut self, additional: usize);
+
+    /// Release as much unused capacity as possible.
+    fn shrink_to_fit(&mut self);
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.as_slice().is_empty()
+    }
+
+    fn get(&self, index: usize) -> Option<&T> {
+        self.as_slice().get(index)
+    }
+
+    fn iter(&self) -> slice::Iter<'_, T> {
+        self.as_slice().iter()
+    }
+}
+
+impl<T> sealed::Sealed for Vec<T> {}
+
+impl<T> Storage<T> for Vec<T> {
+    fn push(&mut self, val: T) {
+        Vec::push(self, val);
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        Vec::pop(self)
+    }
+
+    fn insert(&mut self, index: usize, val: T) {
+        Vec::insert(self, index, val);
+    }
+
+    fn remove(&mut self, index: usize) -> T {
+        Vec::remove(self, index)
+    }
+
+    fn swap_remove(&mut self, index: usize) -> T {
+        Vec::swap_remove(self, index)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        Vec::truncate(self, len);
+    }
+
+    fn as_slice(&self) -> &[T] {
+        self
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        self
+    }
+
+    fn retain<F: FnMut(&T) -> bool>(&mut self, f: F) {
+        Vec::retain(self, f);
+    }
+
+    fn drain_into(&mut self, out: &mut Vec<T>) {
+        out.append(self);
+    }
+
+    fn drain_range_into(&mut self, range: Range<usize>, out: &mut Vec<T>) {
+        out.extend(self.drain(range));
+    }
+
+    fn capacity(&self) -> usize {
+        Vec::capacity(self)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        Vec::reserve(self, additional);
+    }
+
+    fn shrink_to_fit(&mut self) {
+        Vec::shrink_to_fit(self);
+    }
+}
+
+#[cfg(feature = "smallvec")]
+impl<A: smallvec::Array> sealed::Sealed for smallvec::SmallVec<A> {}
+
+#[cfg(feature = "smallvec")]
+impl<A: smallvec::Array> Storage<A::Item> for smallvec::SmallVec<A> {
+    fn push(&mut self, val: A::Item) {
+        smallvec::SmallVec::push(self, val);
+    }
+
+    fn pop(&mut self) -> Option<A::Item> {
+        smallvec::SmallVec::pop(self)
+    }
+
+    fn insert(&mut self, index: usize, val: A::Item) {
+        smallvec::SmallVec::insert(self, index, val);
+    }
+
+    fn remove(&mut self, index: usize) -> A::Item {
+        smallvec::SmallVec::remove(self, index)
+    }
+
+    fn swap_remove(&mut self, index: usize) -> A::Item {
+        smallvec::SmallVec::swap_remove(self, index)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        smallvec::SmallVec::truncate(self, len);
+    }
+
+    fn as_slice(&self) -> &[A::Item] {
+        smallvec::SmallVec::as_slice(self)
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [A::Item] {
+        smallvec::SmallVec::as_mut_slice(self)
+    }
+
+    fn retain<F: FnMut(&A::Item) -> bool>(&mut self, mut f: F) {
+        smallvec::SmallVec::retain(self, |val| f(val));
+    }
+
+    fn drain_into(&mut self, out: &mut Vec<A::Item>) {
+        out.extend(self.drain(..));
+    }
+
+    fn drain_range_into(&mut self, range: Range<usize>, out: &mut Vec<A::Item>) {
+        out.extend(self.drain(range));
+    }
+
+    fn capacity(&self) -> usize {
+        smallvec::SmallVec::capacity(self)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        smallvec::SmallVec::reserve(self, additional);
+    }
+
+    fn shrink_to_fit(&mut self) {
+        smallvec::SmallVec::shrink_to_fit(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Storage;
+    use crate::{assert_contents, assert_local, assert_structure, ScopedVec};
+
+    /// Exercises every code path that touches a scope's storage, run
+    /// against each of the available backends.
+    fn suite<S: Storage<i32>>() {
+        let root = ScopedVec::<i32, S>::with_storage();
+        root.push(1);
+        root.push(2);
+
+        let scoped = root.scope();
+        scoped.push(3);
+        scoped.push(4);
+        scoped.scope().push(5);
+
+        let other = root.split_for_parallel(1).remove(0);
+        other.push(6);
+
+        assert_contents!(root, [1, 2, 3, 4, 5, 6]);
+        assert_local!(root, [1, 2]);
+        assert_structure!(root, [1, 2; [3, 4; [5]], [6]]);
+        assert!(root.contains(&5));
+        assert!(!root.contains_local(&5));
+        assert_eq!(root.positions_of(&4), vec![3]);
+        assert!(!root.is_empty());
+        assert!(!root.is_empty_deep());
+
+        scoped.retain_local(|v| *v != 3);
+        assert_contents!(root, [1, 2, 4, 5, 6]);
+
+        let merged = root.merge_with(&scoped, |l, r| l.or(r).copied());
+        assert_contents!(merged, [1, 2, 4, 5, 6]);
+
+        assert_eq!(root.drain_leaves(), vec![5, 6]);
+        assert_contents!(root, [1, 2, 4]);
+        root.reserve(8);
+        assert!(root.capacity() >= 10);
+        assert_eq!(root.drain(..1).collect::<Vec<_>>(), vec![1]);
+        root.insert(0, 1);
+        root.insert(0, 0);
+        assert_local!(root, [0, 1, 2]);
+        assert_eq!(root.remove(0), 0);
+        assert_eq!(root.remove(0), 1);
+        assert_eq!(scoped.pop(), Some(4));
+        assert_eq!(scoped.pop(), None);
+        assert_eq!(root.to_nested_vec().values, vec![2]);
+
+        root.debug_assert_invariants();
+    }
+
+    #[test]
+    fn vec_storage() {
+        suite::<Vec<i32>>();
+    }
+
+    #[cfg(feature = "smallvec")]
+    #[test]
+    fn smallvec_storage() {
+        suite::<smallvec::SmallVec<[i32; 2]>>();
+        suite::<smallvec::SmallVec<[i32; 8]>>();
+    }
+}
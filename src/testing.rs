@@ -0,0 +1,235 @@
+//! Assertion helpers for testing code built on `ScopedVec`s, with
+//! failure messages which show exactly where the values diverged from
+//! what was expected, along with a rendering of the tree being checked.
+//!
+//! These are usually used through the [assert_contents!],
+//! [assert_local!] and [assert_structure!] macros.
+//!
+//! [assert_contents!]: crate::assert_contents
+//! [assert_local!]: crate::assert_local
+//! [assert_structure!]: crate::assert_structure
+
+use crate::{lock, NestedVec, ScopedVec, Storage};
+use std::fmt::{Debug, Write};
+
+/// Assert that iterating over `scoped` yields exactly the given values,
+/// in order.
+///
+/// ```
+/// # use scoped_vec::{ScopedVec, assert_contents};
+/// let root = ScopedVec::new();
+/// root.push(3);
+/// root.scope().push(4);
+///
+/// assert_contents!(root, [3, 4]);
+/// ```
+#[macro_export]
+macro_rules! assert_contents {
+    ($scoped:expr, [$($v:expr),* $(,)?]) => {
+        $crate::testing::assert_contents(&$scoped, &[$($v),*])
+    };
+}
+
+/// Assert that the values pushed directly into `scoped`, ignoring any of
+/// its children, are exactly the given values, in order.
+///
+/// ```
+/// # use scoped_vec::{ScopedVec, assert_local};
+/// let root = ScopedVec::new();
+/// root.push(3);
+/// root.scope().push(4);
+///
+/// assert_local!(root, [3]);
+/// ```
+#[macro_export]
+macro_rules! assert_local {
+    ($scoped:expr, [$($v:expr),* $(,)?]) => {
+        $crate::testing::assert_local(&$scoped, &[$($v),*])
+    };
+}
+
+/// Assert that `scoped` has exactly the given shape and values, using
+/// the same literal syntax as [nested!](crate::nested).
+///
+/// ```
+/// # use scoped_vec::{ScopedVec, assert_structure};
+/// let root = ScopedVec::new();
+/// root.push(3);
+/// let scope = root.scope();
+/// scope.push(4);
+/// scope.scope().push(5);
+/// root.scope().push(6);
+///
+/// assert_structure!(root, [3; [4; [5]], [6]]);
+/// ```
+#[macro_export]
+macro_rules! assert_structure {
+    ($scoped:expr, $($tree:tt)+) => {
+        $crate::testing::assert_structure(&$scoped, &$crate::nested!($($tree)+))
+    };
+}
+
+/// Build a [NestedVec] from a literal. Each scope is written as its
+/// values followed by a `;` and its children, which are written the same
+/// way - the `;` can be left out for scopes without any children.
+///
+/// ```
+/// # use scoped_vec::{NestedVec, nested};
+/// let tree = nested!([3; [4; [5]], [6]]);
+///
+/// assert_eq!(tree.values, vec![3]);
+/// assert_eq!(tree.children[0].children[0], NestedVec { values: vec![5], children: vec![] });
+/// assert_eq!(tree.children[1].values, vec![6]);
+/// ```
+///
+/// [NestedVec]: crate::NestedVec
+#[macro_export]
+macro_rules! nested {
+    ([$($v:expr),* $(; $($child:tt),* $(,)?)?]) => {
+        $crate::NestedVec {
+            values: vec![$($v),*],
+            children: vec![$($($crate::nested!($child)),*)?],
+        }
+    };
+}
+
+/// See [assert_contents!](crate::assert_contents).
+#[track_caller]
+pub fn assert_contents<T: PartialEq + Debug, S: Storage<T>>(scoped: &ScopedVec<T, S>, expected: &[T]) {
+    let diff = diff(scoped.iter(), expected);
+
+    if let Some(diff) = diff {
+        panic!("contents of ScopedVec didn't match expected values\n{}\ntree: {:#?}", diff, scoped);
+    }
+}
+
+/// See [assert_local!](crate::assert_local).
+#[track_caller]
+pub fn assert_local<T: PartialEq + Debug, S: Storage<T>>(scoped: &ScopedVec<T, S>, expected: &[T]) {
+    let diff = diff(lock::read(&scoped.node.inner).values.iter(), expected);
+
+    if let Some(diff) = diff {
+        panic!("local values of ScopedVec didn't match expected values\n{}\ntree: {:#?}", diff, scoped);
+    }
+}
+
+/// See [assert_structure!](crate::assert_structure).
+#[track_caller]
+pub fn assert_structure<T: PartialEq + Debug, S: Storage<T>>(scoped: &ScopedVec<T, S>, expected: &NestedVec<T>) {
+    let mut path = Vec::new();
+
+    if let Some(diff) = diff_structure(scoped, expected, &mut path) {
+        panic!("structure of ScopedVec didn't match expected tree\n{}\ntree: {:#?}", diff, scoped);
+    }
+}
+
+fn diff_structure<T: PartialEq + Debug, S: Storage<T>>(scoped: &ScopedVec<T, S>, expected: &NestedVec<T>, path: &mut Vec<usize>) -> Option<String> {
+    if let Some(diff) = diff(lock::read(&scoped.node.inner).values.iter(), &expected.values) {
+        return Some(format!("local values of scope at path {:?} diverged\n{}", path, diff));
+    }
+
+    let children = scoped.child_handles();
+    if children.len() != expected.children.len() {
+        return Some(format!(
+            "scope at path {:?} has {} children, expected {}",
+            path,
+            children.len(),
+            expected.children.len()
+        ));
+    }
+
+    for (i, (child, expected)) in children.iter().zip(&expected.children).enumerate() {
+        path.push(i);
+        let diff = diff_structure(child, expected, path);
+        path.pop();
+
+        if diff.is_some() {
+            return diff;
+        }
+    }
+
+    None
+}
+
+/// Compare `actual` against `expected`, returning a line-by-line diff of
+/// the two if they differ.
+fn diff<'a, T, I>(actual: I, expected: &[T]) -> Option<String>
+    where T: PartialEq + Debug + 'a, I: Iterator<Item = &'a T>
+{
+    let mut first_mismatch = None;
+    let mut lines = String::new();
+    let mut len = 0;
+
+    for (i, val) in actual.enumerate() {
+        len += 1;
+
+        match expected.get(i) {
+            Some(expected) if expected == val => {
+                writeln!(lines, "    {}: {:?}", i, val).unwrap();
+            }
+            Some(expected) => {
+                first_mismatch.get_or_insert(i);
+                writeln!(lines, "  - {}: {:?}", i, expected).unwrap();
+                writeln!(lines, "  + {}: {:?}", i, val).unwrap();
+            }
+            None => {
+                first_mismatch.get_or_insert(i);
+                writeln!(lines, "  + {}: {:?}", i, val).unwrap();
+            }
+        }
+    }
+
+    for (i, expected) in expected.iter().enumerate().skip(len) {
+        first_mismatch.get_or_insert(i);
+        writeln!(lines, "  - {}: {:?}", i, expected).unwrap();
+    }
+
+    first_mismatch.map(|i| format!(
+        "first divergence at position {} (expected {} values, found {})\n{}",
+        i,
+        expected.len(),
+        len,
+        lines
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ScopedVec;
+
+    fn panic_message(f: impl FnOnce() + std::panic::UnwindSafe) -> String {
+        let err = std::panic::catch_unwind(f).unwrap_err();
+        err.downcast_ref::<String>().cloned().unwrap()
+    }
+
+    #[test]
+    fn contents_mismatch_shows_divergence() {
+        let root = ScopedVec::new();
+        root.push(3);
+        root.scope().push(5);
+
+        let message = panic_message(move || assert_contents!(root, [3, 4, 5]));
+
+        assert!(message.contains("first divergence at position 1 (expected 3 values, found 2)"), "{}", message);
+        assert!(message.contains("    0: 3\n  - 1: 4\n  + 1: 5\n  - 2: 5\n"), "{}", message);
+        assert!(message.contains("tree: ScopedVec {"), "{}", message);
+    }
+
+    #[test]
+    fn structure_mismatch_shows_path() {
+        let root = ScopedVec::new();
+        root.push(3);
+        let scoped = root.scope();
+        scoped.push(4);
+        scoped.scope().push(5);
+
+        assert_structure!(root, [3; [4; [5]]]);
+        assert_local!(scoped, [4]);
+
+        let message = panic_message(move || assert_structure!(root, [3; [4; [6]]]));
+        assert!(message.contains("local values of scope at path [0, 0] diverged"), "{}", message);
+
+        let message = panic_message(move || assert_structure!(scoped, [4; [5], []]));
+        assert!(message.contains("scope at path [] has 1 children, expected 2"), "{}", message);
+    }
+}
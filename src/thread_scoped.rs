@@ -0,0 +1,109 @@
+use crate::{lock, ScopedVec, Storage};
+use crate::lock::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread::ThreadId;
+
+/// A cloneable handle which routes pushes from each thread into a child
+/// scope of its own, created lazily on the first push from that thread.
+/// Created using [ScopedVec::per_thread].
+///
+/// All the clones of a `ThreadScoped` share the same mapping of threads
+/// to scopes, so a single handle can be cloned into each worker of a
+/// thread pool and the parent scope will see the values from all of
+/// them.
+pub struct ThreadScoped<T, S = Vec<T>> {
+    parent: ScopedVec<T, S>,
+    scopes: Arc<Mutex<HashMap<ThreadId, ScopedVec<T, S>>>>,
+}
+
+impl<T, S> Clone for ThreadScoped<T, S> {
+    fn clone(&self) -> Self {
+        Self {
+            parent: self.parent.clone(),
+            scopes: self.scopes.clone()
+        }
+    }
+}
+
+impl<T, S: Storage<T>> ThreadScoped<T, S> {
+    pub(crate) fn new(parent: ScopedVec<T, S>) -> Self {
+        Self {
+            parent,
+            scopes: Arc::default()
+        }
+    }
+
+    /// Push a value onto the current thread's scope, creating it if this
+    /// is the first push from the thread.
+    pub fn push(&self, val: T) {
+        let scope = {
+            let mut scopes = lock::lock(&self.scopes);
+            let parent = &self.parent;
+            scopes.entry(std::thread::current().id())
+                .or_insert_with(|| parent.scope())
+                .clone()
+        };
+        scope.push(val);
+    }
+
+    /// Returns the scope each thread has pushed to so far, keyed by the
+    /// thread's id.
+    pub fn scopes(&self) -> HashMap<ThreadId, ScopedVec<T, S>> {
+        lock::lock(&self.scopes).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ScopedVec;
+
+    #[test]
+    fn each_thread_gets_its_own_scope() {
+        let root = ScopedVec::new();
+        let handle = root.per_thread();
+
+        let thread_ids: Vec<_> = (0..4)
+            .map(|i| {
+                let handle = handle.clone();
+                std::thread::spawn(move || {
+                    for v in i * 100..(i + 1) * 100 {
+                        handle.push(v);
+                    }
+                    std::thread::current().id()
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect();
+
+        let scopes = handle.scopes();
+        assert_eq!(scopes.len(), 4);
+
+        for (i, id) in thread_ids.iter().enumerate() {
+            assert_eq!(scopes[id], (i * 100..(i + 1) * 100).collect::<Vec<_>>());
+        }
+
+        let mut values: Vec<_> = root.iter().copied().collect();
+        values.sort_unstable();
+        assert_eq!(values, (0..400).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn same_thread_reuses_scope() {
+        let root = ScopedVec::new();
+        let handle = root.per_thread();
+        handle.push(1);
+        handle.clone().push(2);
+
+        let scopes = handle.scopes();
+        assert_eq!(scopes.len(), 1);
+
+        let mut iter = root.iter();
+        assert_eq!(iter.next(), Some(&1));
+        assert_eq!(iter.next(), Some(&2));
+        assert_eq!(iter.next(), None);
+        assert!(!root.has_no_children());
+    }
+}
@@ -0,0 +1,164 @@
+use crate::ScopedVec;
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// An owned copy of a [tracing::Event], as captured by [ScopeLayer].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedEvent {
+    pub level: tracing::Level,
+    pub target: String,
+    pub message: String,
+    /// Every field recorded on the event other than `message`, with
+    /// their values formatted using `Debug`.
+    pub fields: Vec<(&'static str, String)>,
+}
+
+/// A [tracing_subscriber::Layer] which mirrors the span hierarchy into a
+/// `ScopedVec`, creating a new scope for every span as a child of its
+/// parent span's scope and pushing events into the scope of the span
+/// they were recorded in. Events recorded outside of any span are pushed
+/// into the root.
+///
+/// Each span's handle is stashed in its extensions and dropped along
+/// with the span when it closes, but the scope itself stays attached to
+/// its parent so its events can still be read back from the root.
+pub struct ScopeLayer {
+    root: ScopedVec<CapturedEvent>,
+}
+
+impl ScopeLayer {
+    pub fn new() -> Self {
+        Self::with_root(ScopedVec::new())
+    }
+
+    /// Create a layer which captures into the given scope rather than a
+    /// new root.
+    pub fn with_root(root: ScopedVec<CapturedEvent>) -> Self {
+        Self { root }
+    }
+
+    /// Returns a handle to the root scope events are captured into.
+    pub fn root(&self) -> ScopedVec<CapturedEvent> {
+        self.root.clone()
+    }
+}
+
+impl Default for ScopeLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for ScopeLayer
+    where S: Subscriber + for<'a> LookupSpan<'a>
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let parent = span.parent()
+            .and_then(|parent| parent.extensions().get::<ScopedVec<CapturedEvent>>().cloned())
+            .unwrap_or_else(|| self.root.clone());
+
+        let scope = parent.scope();
+        span.extensions_mut().insert(scope);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let scope = ctx.event_span(event)
+            .and_then(|span| span.extensions().get::<ScopedVec<CapturedEvent>>().cloned())
+            .unwrap_or_else(|| self.root.clone());
+
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+
+        let metadata = event.metadata();
+        scope.push(CapturedEvent {
+            level: *metadata.level(),
+            target: metadata.target().to_string(),
+            message: visitor.message,
+            fields: visitor.fields
+        });
+    }
+}
+
+#[derive(Default)]
+struct EventVisitor {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visit for EventVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields.push((field.name(), value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields.push((field.name(), format!("{:?}", value)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScopeLayer;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn spans_are_mirrored_into_scopes() {
+        let layer = ScopeLayer::new();
+        let root = layer.root();
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("outside");
+
+            let outer = tracing::info_span!("outer");
+            let _outer = outer.enter();
+            tracing::info!(answer = 42, "in outer");
+
+            {
+                let inner = tracing::info_span!("inner");
+                let _inner = inner.enter();
+                tracing::warn!("in inner");
+            }
+
+            {
+                let sibling = tracing::info_span!("sibling");
+                let _sibling = sibling.enter();
+                tracing::info!("in sibling");
+            }
+        });
+
+        let nested = root.to_nested_vec();
+        let messages = |values: &[super::CapturedEvent]| {
+            values.iter().map(|e| e.message.clone()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(messages(&nested.values), vec!["outside"]);
+        assert_eq!(nested.children.len(), 1);
+
+        let outer = &nested.children[0];
+        assert_eq!(messages(&outer.values), vec!["in outer"]);
+        assert_eq!(outer.values[0].fields, vec![("answer", "42".to_string())]);
+        assert_eq!(outer.children.len(), 2);
+
+        assert_eq!(messages(&outer.children[0].values), vec!["in inner"]);
+        assert_eq!(outer.children[0].values[0].level, tracing::Level::WARN);
+        assert_eq!(messages(&outer.children[1].values), vec!["in sibling"]);
+        assert!(outer.children.iter().all(|c| c.children.is_empty()));
+    }
+}
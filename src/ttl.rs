@@ -0,0 +1,167 @@
+use crate::local::Local;
+use crate::{lock, ScopedVec, Storage};
+use crate::lock::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A source of the current time, used to track how long scopes created
+/// using [ScopedVec::scope_with_ttl_and_clock] have been idle.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// A [Clock] reading the system's monotonic clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// How long a scope may go without values being pushed into it or its
+/// descendants before it's purged.
+pub(crate) struct Expiry {
+    ttl: Duration,
+    last_activity: Mutex<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Expiry {
+    fn touch(&self) {
+        *lock::lock(&self.last_activity) = self.clock.now();
+    }
+
+    fn is_expired(&self) -> bool {
+        let last_activity = *lock::lock(&self.last_activity);
+        self.clock.now().saturating_duration_since(last_activity) > self.ttl
+    }
+}
+
+impl<T, S> Local<T, S> {
+    /// Record activity against this scope's expiry and those of its
+    /// ancestors, when a value is pushed into it.
+    pub(crate) fn touch(&self) {
+        for expiry in &self.touches {
+            expiry.touch();
+        }
+    }
+}
+
+impl<T, S: Storage<T>> ScopedVec<T, S> {
+    /// Create a new child scope which expires once `ttl` has passed
+    /// without a value being pushed into it or any of its descendants.
+    /// Expired scopes aren't removed until
+    /// [ScopedVec::purge_expired_scopes] is called on one of their
+    /// ancestors, and are visible like any other scope until then.
+    ///
+    /// Scopes created under this one keep track of its expiry, so pushes
+    /// to them extend its lifetime without any searching up the tree.
+    /// Scopes moved under it afterwards, such as with
+    /// [ScopedVec::replace_subtree_at_path], don't.
+    pub fn scope_with_ttl(&self, ttl: Duration) -> ScopedVec<T, S> {
+        self.scope_with_ttl_and_clock(ttl, Arc::new(SystemClock))
+    }
+
+    /// Like [ScopedVec::scope_with_ttl], reading the time from `clock`.
+    pub fn scope_with_ttl_and_clock(&self, ttl: Duration, clock: Arc<dyn Clock>) -> ScopedVec<T, S> {
+        let expiry = Arc::new(Expiry {
+            ttl,
+            last_activity: Mutex::new(clock.now()),
+            clock
+        });
+
+        let scope = self.scope();
+        let mut local = lock::write(&scope.node.inner);
+        local.touches.push(expiry.clone());
+        local.expiry = Some(expiry);
+        drop(local);
+
+        scope
+    }
+
+    /// Remove every descendant scope which has expired, along with its
+    /// own descendants, returning the total number of scopes removed.
+    /// Scopes which haven't expired are searched for expired scopes of
+    /// their own.
+    pub fn purge_expired_scopes(&self) -> usize {
+        self.retain_scopes_reporting(|scope| {
+            match &lock::read(&scope.node.inner).expiry {
+                Some(expiry) => !expiry.is_expired(),
+                None => true,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assert_structure, lock, Clock, ScopedVec};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    struct ManualClock(Mutex<Instant>);
+
+    impl ManualClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn pushes_extend_the_lifetime() {
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+        let ttl = Duration::from_secs(10);
+
+        let root = ScopedVec::new();
+        root.push(0);
+        let session = root.scope_with_ttl_and_clock(ttl, clock.clone());
+        let nested = session.scope();
+
+        clock.advance(Duration::from_secs(8));
+        nested.push(1);
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(root.purge_expired_scopes(), 0);
+        assert_structure!(root, [0; [; [1]]]);
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(root.purge_expired_scopes(), 2);
+        assert_structure!(root, [0]);
+    }
+
+    #[test]
+    fn nested_scopes_expire_independently() {
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+
+        let root = ScopedVec::new();
+        let outer = root.scope_with_ttl_and_clock(Duration::from_secs(10), clock.clone());
+        let inner = outer.scope_with_ttl_and_clock(Duration::from_secs(5), clock.clone());
+        inner.push(1);
+
+        clock.advance(Duration::from_secs(6));
+        outer.push(2);
+        assert_eq!(root.purge_expired_scopes(), 1);
+        assert_structure!(root, [; [2]]);
+
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(root.purge_expired_scopes(), 1);
+        assert_structure!(root, []);
+    }
+
+    #[test]
+    fn expiring_scopes_dont_register_observers() {
+        let root = ScopedVec::new();
+        let session = root.scope_with_ttl(Duration::from_secs(10));
+        session.scope().push(1);
+
+        assert!(lock::read(&session.node.observers).is_empty());
+        assert!(lock::read(&root.node.observers).is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{lock, ScopedVec, Storage};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// The number of length watchers registered across every tree, so
+/// changes can skip looking for watchers when there aren't any.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Publishes the total number of values in a subtree whenever it
+/// changes, see [ScopedVec::watch_len].
+pub(crate) struct LenWatcher {
+    tx: watch::Sender<usize>,
+    /// Set when the length may have changed since it was last counted.
+    dirty: AtomicBool,
+    /// Set while a thread is counting, so concurrent changes are
+    /// coalesced into a single recount rather than each walking the
+    /// tree.
+    counting: AtomicBool,
+}
+
+impl LenWatcher {
+    /// Recount `scope`, which this watcher is registered on, and publish
+    /// the result if it's changed. If another thread is already counting
+    /// it's left to that thread to count again once it's done. Returns
+    /// false once every receiver has been dropped.
+    fn changed<T, S: Storage<T>>(&self, scope: &ScopedVec<T, S>) -> bool {
+        if self.tx.is_closed() {
+            return false;
+        }
+
+        self.dirty.store(true, Ordering::SeqCst);
+        while self.dirty.load(Ordering::SeqCst) {
+            if self.counting.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                break;
+            }
+            self.dirty.store(false, Ordering::SeqCst);
+
+            let len = count(scope);
+            self.tx.send_if_modified(|current| {
+                let modified = *current != len;
+                *current = len;
+                modified
+            });
+            self.counting.store(false, Ordering::SeqCst);
+        }
+
+        true
+    }
+}
+
+fn count<T, S: Storage<T>>(scope: &ScopedVec<T, S>) -> usize {
+    let mut len = 0;
+    scope.for_each_scope(|_| true, |scope| len += lock::read(&scope.node.inner).values.len());
+    len
+}
+
+impl<T, S: Storage<T>> ScopedVec<T, S> {
+    /// Returns a receiver which is sent the total number of values in
+    /// this scope and its descendants whenever it changes, starting with
+    /// the current count.
+    ///
+    /// Changes are coalesced rather than queued, so a receiver which
+    /// falls behind only sees the most recent count, and a burst of
+    /// changes from several threads at once is counted a single time
+    /// once they've settled. Changes which leave the count where it was,
+    /// such as a push followed by a pop, aren't sent at all. Each change
+    /// recounts the whole subtree, one scope at a time.
+    pub fn watch_len(&self) -> watch::Receiver<usize> {
+        let (tx, rx) = watch::channel(count(self));
+        let watcher = Arc::new(LenWatcher {
+            tx,
+            dirty: AtomicBool::new(false),
+            counting: AtomicBool::new(false)
+        });
+
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        lock::write(&self.node.watchers).push(watcher.clone());
+        // catch anything that changed between the first count and the
+        // watcher being registered
+        watcher.changed(self);
+
+        rx
+    }
+
+    /// Let the length watchers registered on this scope and its
+    /// ancestors know the number of values in it may have changed. Must
+    /// be called without holding any of the tree's locks.
+    pub(crate) fn notify_len_changed(&self) {
+        if ACTIVE.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        let mut scope = Some(self.clone());
+        while let Some(current) = scope {
+            let watchers = lock::read(&current.node.watchers).clone();
+            for watcher in watchers {
+                if !watcher.changed(&current) {
+                    let mut watchers = lock::write(&current.node.watchers);
+                    if let Some(i) = watchers.iter().position(|w| Arc::ptr_eq(w, &watcher)) {
+                        watchers.remove(i);
+                        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+            }
+
+            scope = lock::read(&current.node.parent).upgrade().map(|node| ScopedVec { node, counted: false });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{nested, ScopedVec};
+
+    #[test]
+    fn publishes_length_changes() {
+        let root = ScopedVec::from_nested(nested!([1; [2]]));
+        let child = root.scope_at_path(&[0]).unwrap();
+
+        let mut rx = root.watch_len();
+        assert_eq!(*rx.borrow_and_update(), 2);
+
+        child.push(3);
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(*rx.borrow_and_update(), 3);
+
+        child.scope().push(4);
+        root.push(5);
+        assert_eq!(*rx.borrow_and_update(), 5);
+
+        root.retain_local(|_| true);
+        assert!(!rx.has_changed().unwrap());
+
+        root.clear_descendants(true);
+        assert_eq!(*rx.borrow_and_update(), 2);
+    }
+
+    #[test]
+    fn dropped_receivers_unregister() {
+        let root = ScopedVec::new();
+        drop(root.watch_len());
+        root.push(1);
+        assert!(crate::lock::read(&root.node.watchers).is_empty());
+    }
+}